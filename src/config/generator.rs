@@ -1,28 +1,290 @@
 use serde::{Serialize, Deserialize};
+use crate::terrain::material::VoxelMaterial;
 
+/// Parameters shared by the fractal noise sources.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct GeneratorConfig {
-    pub seed: i32,
-    pub lacunarity: f32,
+pub struct Fractal {
     pub freq: f32,
+    pub lacunarity: f32,
     pub gain: f32,
     pub octaves: u8,
+    /// added to the generator's seed, so the same source can appear twice without repeating
+    #[serde(default)]
+    pub seed: i32,
+}
+
+/// A node in the tree of noise the height map is built from. Sources are
+/// sampled at a column's world (x, z), operators combine what their inputs return.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum NoiseNode {
+    Constant(f32),
+    Fbm(Fractal),
+    Ridge(Fractal),
+    Turbulence(Fractal),
+    /// distance to the nearest cell center
+    Cellular { freq: f32, jitter: f32, #[serde(default)] seed: i32 },
+
+    Add(Vec<NoiseNode>),
+    Multiply(Vec<NoiseNode>),
+    Min(Vec<NoiseNode>),
+    Max(Vec<NoiseNode>),
+    Clamp { input: Box<NoiseNode>, min: f32, max: f32 },
+    /// maps the input through a piecewise linear curve of (input, output) points, sorted by input.
+    /// Inputs past either end get the end's output
+    Curve { input: Box<NoiseNode>, points: Vec<(f32, f32)> },
+    /// samples the input somewhere else, offset by `strength` times what `x` and `z` return
+    Warp { input: Box<NoiseNode>, x: Box<NoiseNode>, z: Box<NoiseNode>, strength: f32 },
+}
+
+/// Moves where every column samples its noise by an fbm offset, which hides the grid
+/// the noise is built on. The 3d noise of a column moves along with its 2d noise.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WarpConfig {
+    /// furthest a column's samples move, in voxels
+    pub strength: f32,
+    pub freq: f32,
+    pub octaves: u8,
+}
+
+/// A pass over the height map, see `Erosion`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ErosionStage {
+    Hydraulic(HydraulicConfig),
+    Thermal(ThermalConfig),
+}
+
+/// Droplets running downhill, carving valleys and leaving sediment where they slow down.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HydraulicConfig {
+    /// droplets dropped on every erosion tile
+    pub droplets: u32,
+    /// steps a droplet runs for at most
+    pub lifetime: u32,
+    /// how much of its direction a droplet keeps each step rather than following the slope, 0..1
+    pub inertia: f32,
+    /// sediment a droplet can carry per unit of slope, speed and water
+    pub capacity: f32,
+    /// share of its spare capacity a droplet picks up each step
+    pub erosion_rate: f32,
+    /// share of the sediment over its capacity a droplet drops each step
+    pub deposition_rate: f32,
+    /// share of its water a droplet loses each step
+    pub evaporation_rate: f32,
+}
+
+/// Material sliding off slopes steeper than the talus angle, which leaves scree slopes and
+/// wears down thin spikes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThermalConfig {
+    /// steepest slope material rests at, in degrees
+    pub talus_angle: f32,
+    /// share of the material above the talus slope that slides down each iteration
+    pub rate: f32,
+    pub iterations: u32,
+}
+
+/// Basins in the height map that fill up with water, see `Lakes`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LakeConfig {
+    /// basins shallower than this at their deepest stay dry
+    pub min_depth: f32,
+}
+
+/// Where the density of a voxel comes from.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DensityMode {
+    /// height of the voxel below the ridge height map, so no overhangs
+    HeightMap,
+    /// the height map density with the 3d noise added, which bends the surface into overhangs and arches
+    Overhangs,
+    /// the 3d noise alone, falling off with height by `VolumeConfig::gradient`
+    Volume,
+}
+
+/// 3d noise sampled for every voxel, for the density modes and caves that need it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VolumeConfig {
+    pub freq: f32,
+    pub octaves: u8,
+    /// density the noise adds at its peaks
+    pub amplitude: f32,
+    /// density lost per voxel of height in `Volume` mode, keeps the noise from filling the sky
+    pub gradient: f32,
+
+    pub cave_freq: f32,
+    /// voxels where the ridged cave noise rises above this are carved out, 1.0 or more leaves no caves
+    pub cave_threshold: f32,
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        VolumeConfig {
+            freq: 0.03,
+            octaves: 3,
+            amplitude: 12.0,
+            gradient: 0.5,
+
+            cave_freq: 0.04,
+            cave_threshold: 1.0,
+        }
+    }
+}
+
+/// A kind of terrain, found wherever the climate is close to its own.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BiomeConfig {
+    pub name: String,
+    /// where the biome sits on the climate maps, which both range over about -1..1
+    pub temperature: f32,
+    pub moisture: f32,
+    /// surface height of the biome's columns
+    pub height: NoiseNode,
+    /// material of the top voxel
+    pub surface: VoxelMaterial,
+    /// material between the surface and the rock
+    pub soil: VoxelMaterial,
+    /// voxels of surface and soil over the rock
+    pub soil_depth: f32,
+}
+
+/// The low frequency temperature and moisture noise biomes are picked by.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClimateConfig {
+    pub freq: f32,
+    pub octaves: u8,
+    /// how much further from a column's climate than the closest biome another biome can be
+    /// and still have its height blended in, which is what keeps biome borders smooth
+    pub blend: f32,
+}
 
-    pub scaling: f32,
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GeneratorConfig {
+    pub seed: i32,
+    /// applied before the climate, height and 3d noise, whatever their sources
+    pub warp: Option<WarpConfig>,
+    pub climate: ClimateConfig,
+    /// there has to be at least one
+    pub biomes: Vec<BiomeConfig>,
+    /// run in order over the blended biome heights, empty to leave them as they are
+    pub erosion: Vec<ErosionStage>,
     pub cutoff: bool,
+    pub mode: DensityMode,
+    pub volume: VolumeConfig,
+
+    /// surfaces below this height are sand, whatever the biome
+    pub sand_height: f32,
+    /// surfaces above this height are bare rock, whatever the biome
+    pub rock_height: f32,
+
+    /// columns whose surface is below this are under the sea
+    pub sea_level: f32,
+    /// None leaves basins above the sea dry
+    pub lakes: Option<LakeConfig>,
 }
 
 impl Default for GeneratorConfig {
     fn default() -> Self {
         GeneratorConfig {
             seed: 25565,
-            lacunarity: 0.5,
-            freq: 0.04,
-            gain: 4.0,
-            octaves: 4,
-
-            scaling: 25.0,
+            warp: Some(WarpConfig { strength: 6.0, freq: 0.01, octaves: 2 }),
+            climate: ClimateConfig { freq: 0.002, octaves: 2, blend: 0.3 },
+            biomes: vec![
+                BiomeConfig {
+                    name: "plains".to_string(),
+                    temperature: 0.2,
+                    moisture: 0.3,
+                    height: NoiseNode::Add(vec![
+                        NoiseNode::Multiply(vec![
+                            NoiseNode::Fbm(Fractal { freq: 0.01, lacunarity: 2.0, gain: 0.5, octaves: 4, seed: 0 }),
+                            NoiseNode::Constant(6.0),
+                        ]),
+                        NoiseNode::Constant(8.0),
+                    ]),
+                    surface: VoxelMaterial::Grass,
+                    soil: VoxelMaterial::Dirt,
+                    soil_depth: 4.0,
+                },
+                BiomeConfig {
+                    name: "mountains".to_string(),
+                    temperature: -0.1,
+                    moisture: -0.2,
+                    height: NoiseNode::Multiply(vec![
+                        NoiseNode::Ridge(Fractal { freq: 0.04, lacunarity: 0.5, gain: 4.0, octaves: 4, seed: 0 }),
+                        NoiseNode::Constant(25.0),
+                    ]),
+                    surface: VoxelMaterial::Grass,
+                    soil: VoxelMaterial::Dirt,
+                    soil_depth: 3.0,
+                },
+                BiomeConfig {
+                    name: "desert".to_string(),
+                    temperature: 0.7,
+                    moisture: -0.6,
+                    height: NoiseNode::Add(vec![
+                        NoiseNode::Multiply(vec![
+                            NoiseNode::Ridge(Fractal { freq: 0.02, lacunarity: 2.0, gain: 0.5, octaves: 2, seed: 0 }),
+                            NoiseNode::Constant(5.0),
+                        ]),
+                        NoiseNode::Constant(5.0),
+                    ]),
+                    surface: VoxelMaterial::Sand,
+                    soil: VoxelMaterial::Sand,
+                    soil_depth: 6.0,
+                },
+                BiomeConfig {
+                    name: "tundra".to_string(),
+                    temperature: -0.7,
+                    moisture: 0.2,
+                    height: NoiseNode::Add(vec![
+                        NoiseNode::Multiply(vec![
+                            NoiseNode::Fbm(Fractal { freq: 0.015, lacunarity: 2.0, gain: 0.5, octaves: 3, seed: 0 }),
+                            NoiseNode::Constant(8.0),
+                        ]),
+                        NoiseNode::Constant(10.0),
+                    ]),
+                    surface: VoxelMaterial::Snow,
+                    soil: VoxelMaterial::Dirt,
+                    soil_depth: 2.0,
+                },
+            ],
+            erosion: vec![
+                ErosionStage::Thermal(ThermalConfig {
+                    talus_angle: 50.0,
+                    rate: 0.5,
+                    iterations: 8,
+                }),
+                ErosionStage::Hydraulic(HydraulicConfig {
+                    droplets: 4000,
+                    lifetime: 30,
+                    inertia: 0.05,
+                    capacity: 4.0,
+                    erosion_rate: 0.3,
+                    deposition_rate: 0.3,
+                    evaporation_rate: 0.02,
+                }),
+            ],
             cutoff: false,
+            mode: DensityMode::HeightMap,
+            volume: VolumeConfig::default(),
+
+            sand_height: 3.0,
+            rock_height: 30.0,
+
+            sea_level: 2.0,
+            lakes: Some(LakeConfig { min_depth: 1.0 }),
         }
     }
 }
+
+impl GeneratorConfig {
+    /// Hash of every setting, which changes whenever any of them does. Saved edits are
+    /// stored as differences from the generated terrain, so they are only valid for the
+    /// config they were made with. FNV-1a over the serialized config, which unlike
+    /// `DefaultHasher` stays the same across builds.
+    pub fn fingerprint(&self) -> u64 {
+        let serialized = ron::ser::to_string(self).expect("generator config should serialize");
+        serialized.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
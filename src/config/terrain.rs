@@ -0,0 +1,56 @@
+use serde::{Serialize, Deserialize};
+
+/// How chunk densities are held in memory and on disk. The quantized formats
+/// clamp densities to `min..max` and run-length encode them.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DensityFormat {
+    Float,
+    I8 { min: f32, max: f32 },
+    U8 { min: f32, max: f32 },
+}
+
+/// Limit on the chunks `ChunkManager` keeps loaded. Past it, the least
+/// recently used chunks out of view are evicted.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ChunkBudget {
+    Chunks(usize),
+    /// counts chunk densities only, meshes live on the GPU
+    Bytes(usize),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TerrainConfig {
+    /// radius around the camera, in chunks, that is kept loaded
+    pub view_distance: u32,
+    /// chunks kept loaded above and below the camera's chunk
+    pub vertical_view_distance: u32,
+    /// coarser levels of detail drawn past the full resolution chunks, each doubling
+    /// the voxel stride and how far out it reaches
+    pub lod_levels: u8,
+    /// threads generating and meshing chunks in the background
+    pub worker_threads: usize,
+    /// where modified chunks are saved, relative to the application root
+    pub region_dir: String,
+    pub density_format: DensityFormat,
+    pub chunk_budget: ChunkBudget,
+    /// terrain edits that can be undone
+    pub history_depth: usize,
+    /// most memory the undo history may take up, in bytes
+    pub history_bytes: usize,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        TerrainConfig {
+            view_distance: 6,
+            vertical_view_distance: 2,
+            lod_levels: 3,
+            worker_threads: 3,
+            region_dir: "save/regions".to_string(),
+            density_format: DensityFormat::I8 { min: -4.0, max: 4.0 },
+            chunk_budget: ChunkBudget::Chunks(2048),
+            history_depth: 64,
+            history_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
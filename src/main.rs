@@ -24,6 +24,9 @@ use amethyst::{
 use amethyst::ui::{RenderUi, UiBundle};
 use crate::march::March;
 use crate::config::generator::GeneratorConfig;
+use crate::config::terrain::TerrainConfig;
+use crate::terrain::stream::ChunkStreamSystem;
+use crate::terrain::edit::TerrainEditSystemDesc;
 
 mod march;
 mod terrain;
@@ -43,6 +46,9 @@ fn main() -> amethyst::Result<()> {
   let generator_path = app_root.join("config").join("generator.ron");
   let generator_config = GeneratorConfig::load(&generator_path)?;
 
+  let terrain_path = app_root.join("config").join("terrain.ron");
+  let terrain_config = TerrainConfig::load(&terrain_path)?;
+
   let game_data = GameDataBuilder::default()
     .with(AutoFovSystem::default(), "auto_fov", &[])
     .with_bundle(FpsCounterBundle::default())?
@@ -60,6 +66,8 @@ fn main() -> amethyst::Result<()> {
       "fly_movement",
       "free_rotation"
     ]))?
+    .with(ChunkStreamSystem::default(), "chunk_stream", &["fly_movement"])
+    .with_system_desc(TerrainEditSystemDesc::default(), "terrain_edit", &["chunk_stream"])
     .with_bundle(UiBundle::<StringBindings>::new())?
     .with_bundle(
       RenderingBundle::<DefaultBackend>::new()
@@ -75,6 +83,7 @@ fn main() -> amethyst::Result<()> {
   let assets_dir = app_root.join("assets");
   let mut game : Application<_> = ApplicationBuilder::new(assets_dir, March::default())
     ?.with_resource(generator_config)
+    .with_resource(terrain_config)
     .with_frame_limit(FrameRateLimitStrategy::Yield, 60)
     .build(game_data)?;
   game.run();
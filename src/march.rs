@@ -11,6 +11,8 @@ use amethyst::{
     light::{Light, DirectionalLight, PointLight},
     rendy::mesh::{ MeshBuilder, Position, Color, TexCoord, Indices},
     rendy::util::types::vertex::{PosColor, PosTex},
+    rendy::texture::{TextureBuilder, pixel::Rgba32Sfloat},
+    rendy::hal::image::{Kind, ViewKind, SamplerInfo, Filter, WrapMode},
     debug_drawing::DebugLines,
     palette::{ LinSrgba, Srgb, Srgba },
     visibility::BoundingSphere,
@@ -21,16 +23,24 @@ use amethyst::{
     Material
   },
   controls::FlyControlTag,
-  utils::auto_fov::AutoFov,
+  utils::{auto_fov::AutoFov, application_root_dir},
   core::{
     transform::Transform,
     math::base::Vector3
   },
 };
 
-use crate::terrain::terrain_gen::{TerrainGenerator, WIDTH, DEPTH};
+use crate::terrain::terrain_gen::TerrainGenerator;
 use crate::terrain::surface_net::SurfaceNet;
+use crate::terrain::chunk::ChunkManager;
+use crate::terrain::lod::LodManager;
+use crate::terrain::history::EditHistory;
+use crate::terrain::region::RegionStore;
+use crate::terrain::worker::ChunkSource;
+use crate::terrain::stream::{TerrainMaterial, WaterMaterial};
+use crate::terrain::material::MATERIALS;
 use crate::config::generator::GeneratorConfig;
+use crate::config::terrain::TerrainConfig;
 use std::fs::File;
 use std::io::Write;
 use ron::ser::PrettyConfig;
@@ -69,11 +79,22 @@ impl SimpleState for March {
     Trans::None
   }
 
+  fn on_stop(&mut self, data: StateData<GameData>) {
+    if let Err(e) = data.world.read_resource::<ChunkManager>().save_all() {
+      eprintln!("failed to save terrain: {}", e);
+    }
+  }
+
 }
 
+/// how far above the ground the camera starts out
+const CAMERA_HEIGHT: f32 = 20.0;
+
 fn initialize_camera(world: &mut World) {
+  // through the chunks' generator, which keeps the tiles this erodes for the first chunks around the camera
+  let ground = world.read_resource::<ChunkManager>().generated_surface_height(0.0, 0.0);
   let mut transform = Transform::default();
-  transform.set_translation_xyz(0.0, 80.0, 0.0);
+  transform.set_translation_xyz(0.0, ground + CAMERA_HEIGHT, 0.0);
 
   let mut auto_fov = AutoFov::default();
   auto_fov.set_base_fovx(std::f32::consts::FRAC_PI_3);
@@ -167,44 +188,64 @@ fn mk_material(world: &mut World) -> Handle<Material> {
   mat_handle
 }
 
-fn initialize_terrain(world: &mut World) {
-  // let default_mat = world.read_resource::<MaterialDefaults>().0.clone();
-  let gen_config = (&*world.read_resource::<GeneratorConfig>()).clone();
-  println!("generating terrain");
-  let terrain_gen = TerrainGenerator::new(gen_config);
-  let mat_handle = mk_material(world);
-  for i in -5..5 {
-    for j in -5..5 {
-      initialize_chunk(world, &terrain_gen, mat_handle.clone(), (i, j))
-    }
-  }
+/// One texel per voxel material, which terrain vertices select through their tex coords.
+fn mk_terrain_material(world: &mut World) -> Handle<Material> {
+  let texels = MATERIALS.iter()
+    .map(|material| Rgba32Sfloat { repr: material.color() })
+    .collect::<Vec<_>>();
+  mk_palette_material(world, texels)
 }
 
-fn initialize_chunk(world: &mut World, terrain_gen: &TerrainGenerator, mat_handle: Handle<Material>, (x, z): (i32, i32)) {
-  println!("generating chunk ({}, {})", x, z);
-  let chunk = terrain_gen.generate_chunk(x, z);
-  println!("generating surface net");
-  let surface_net = SurfaceNet::new();
-  println!("generating surface net cubes");
-  let cubes = surface_net.mk_cubes(&chunk);
-  println!("generating mesh");
-  let mesh_data = surface_net.mk_mesh(cubes);
-
-  println!("loading mesh");
-  let mesh_handle = {
-    let loader = world.read_resource::<Loader>();
-    let mesh_asset_storage = world.read_resource::<AssetStorage<Mesh>>();
-    let mesh_handle = loader.load_from_data(mesh_data, (), &*mesh_asset_storage);
-    mesh_handle
-  };
+/// water is a single translucent blue, its mesh samples the middle of the texture
+fn mk_water_material(world: &mut World) -> Handle<Material> {
+  mk_palette_material(world, vec![Rgba32Sfloat { repr: [0.15, 0.35, 0.6, 0.6] }])
+}
 
-  let mut transform = Transform::from(Vector3::new((x * WIDTH as i32) as f32, 0.0, (z * DEPTH as i32) as f32));
+/// a material whose albedo is a row of texels, one per u coordinate
+fn mk_palette_material(world: &mut World, texels: Vec<Rgba32Sfloat>) -> Handle<Material> {
+  let len = texels.len() as u32;
+  let palette = TextureBuilder::new()
+    .with_kind(Kind::D2(len, 1, 1, 1))
+    .with_view_kind(ViewKind::D2)
+    .with_data_width(len)
+    .with_data_height(1)
+    .with_sampler_info(SamplerInfo::new(Filter::Nearest, WrapMode::Clamp))
+    .with_data(texels);
+  let texture_handle = world.read_resource::<Loader>().load_from_data(palette.into(), (), &world.read_resource());
 
-  println!("creating entity");
-  world.create_entity()
-    .with(mesh_handle)
-    .with(BoundingSphere::origin(256.0))
-    .with(mat_handle)
-    .with(transform)
-    .build();
+  let default_mat = world.read_resource::<MaterialDefaults>().0.clone();
+  let mat = Material {
+    albedo: texture_handle,
+    ..default_mat
+  };
+  world.read_resource::<Loader>().load_from_data(mat, (), &world.read_resource::<AssetStorage<Material>>())
+}
+
+fn initialize_terrain(world: &mut World) {
+  // let default_mat = world.read_resource::<MaterialDefaults>().0.clone();
+  let gen_config = (&*world.read_resource::<GeneratorConfig>()).clone();
+  let terrain_config = (&*world.read_resource::<TerrainConfig>()).clone();
+  let region_dir = application_root_dir()
+    .expect("failed to find the application root")
+    .join(&terrain_config.region_dir);
+  let source = ChunkSource::new(
+    TerrainGenerator::new(gen_config.clone()).with_view(&terrain_config, 1),
+    RegionStore::new(region_dir, gen_config.fingerprint()),
+    terrain_config.density_format,
+  );
+  let chunk_manager = ChunkManager::new(source, SurfaceNet::new(), &terrain_config);
+  let lod_manager = LodManager::new(
+    // the chunks are level 0, this one makes the rest
+    TerrainGenerator::new(gen_config).with_view(&terrain_config, terrain_config.lod_levels as usize),
+    SurfaceNet::new(),
+    &terrain_config,
+  );
+  let mat_handle = mk_terrain_material(world);
+  let water_handle = mk_water_material(world);
+  // chunks are streamed in around the camera by ChunkStreamSystem
+  world.insert(chunk_manager);
+  world.insert(lod_manager);
+  world.insert(EditHistory::new(&terrain_config));
+  world.insert(TerrainMaterial(mat_handle));
+  world.insert(WaterMaterial(water_handle));
 }
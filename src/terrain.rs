@@ -1,4 +1,23 @@
 pub mod surface_net;
 pub mod terrain_gen;
+pub mod noise;
+pub mod biome;
+pub mod erosion;
+pub mod tile_cache;
+pub mod water;
+pub mod cache;
 pub mod chunk;
+pub mod coord;
+pub mod raycast;
+pub mod sample;
+pub mod region;
+pub mod delta;
+pub mod storage;
 pub mod constant;
+pub mod material;
+pub mod stream;
+pub mod worker;
+pub mod brush;
+pub mod edit;
+pub mod history;
+pub mod lod;
@@ -0,0 +1,43 @@
+use simdnoise::scalar;
+use crate::config::generator::GeneratorConfig;
+
+/// added to the generator's seed for the climate maps, away from what height graphs tend to use
+const TEMPERATURE_SEED: i32 = 1000;
+const MOISTURE_SEED: i32 = 1001;
+
+/// Temperature and moisture of the column at (x, z).
+pub fn climate(config: &GeneratorConfig, x: f32, z: f32) -> (f32, f32) {
+  let climate = &config.climate;
+  let sample = |seed: i32| unsafe {
+    scalar::fbm_2d(x * climate.freq, z * climate.freq, 2.0, 0.5, climate.octaves, config.seed + seed)
+  };
+  (sample(TEMPERATURE_SEED), sample(MOISTURE_SEED))
+}
+
+/// How much each biome contributes to a column with the given climate, as
+/// (index into `GeneratorConfig::biomes`, weight). The weights sum to one and
+/// the closest biome comes first. They change gradually with the climate, so
+/// neither do heights blended with them.
+pub fn biome_weights(config: &GeneratorConfig, (temperature, moisture): (f32, f32)) -> Vec<(usize, f32)> {
+  let distances = config.biomes.iter()
+    .map(|biome| (biome.temperature - temperature).hypot(biome.moisture - moisture))
+    .collect::<Vec<_>>();
+  let closest = distances.iter().copied().fold(std::f32::INFINITY, f32::min);
+  let blend = config.climate.blend.max(1e-6);
+
+  let mut weights = distances.into_iter()
+    .enumerate()
+    .filter_map(|(i, distance)| {
+      // falls off smoothly from 1 for the closest biome to 0 for those `blend` further out
+      let t = (1.0 - (distance - closest) / blend).max(0.0);
+      let weight = t * t * (3.0 - 2.0 * t);
+      if weight > 0.0 { Some((i, weight)) } else { None }
+    })
+    .collect::<Vec<_>>();
+  let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+  for (_, weight) in &mut weights {
+    *weight /= total;
+  }
+  weights.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+  weights
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use amethyst::{core::math::Vector3, shrev::EventChannel};
+use simdnoise::NoiseBuilder;
+use super::chunk::{ChunkEvent, ChunkManager};
+use super::coord::{VoxelAabb, VoxelPos};
+
+/// How a brush's effect fades from its center (t = 0) to its edge (t = 1).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Falloff {
+  /// full effect right up to the edge
+  Constant,
+  Linear,
+  /// smoothstep, flat in the middle and soft at the edge
+  Smooth,
+}
+
+impl Falloff {
+  pub fn weight(self, t: f32) -> f32 {
+    if t >= 1.0 {
+      return 0.0;
+    }
+    let t = t.max(0.0);
+    match self {
+      Falloff::Constant => 1.0,
+      Falloff::Linear => 1.0 - t,
+      Falloff::Smooth => 1.0 - t * t * (3.0 - 2.0 * t),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BrushKind {
+  /// raises the density inside a sphere
+  Add,
+  /// lowers the density inside a sphere
+  Subtract,
+  /// lowers the density inside a cube, `radius` being half its side
+  Carve,
+  /// blends each voxel toward the average of its 3x3x3 neighbourhood
+  Smooth,
+  /// blends toward the plane through the stroke's center with the given upward normal
+  Flatten { normal: Vector3<f32> },
+  /// adds noise of the given frequency, in cycles per voxel
+  Roughen { frequency: f32, seed: i32 },
+}
+
+/// A sculpting tool. `strength` is the most density a stroke adds or removes, or for
+/// the blending brushes how far toward their target it goes, 0 to 1, at the center.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Brush {
+  pub kind: BrushKind,
+  pub radius: f32,
+  pub strength: f32,
+  pub falloff: Falloff,
+}
+
+impl Brush {
+  pub fn new(kind: BrushKind, radius: f32, strength: f32, falloff: Falloff) -> Self {
+    Brush { kind, radius, strength, falloff }
+  }
+
+  /// every voxel the brush can reach around `center`
+  pub fn bounds(&self, center: Vector3<f32>) -> VoxelAabb {
+    let reach = Vector3::repeat(self.radius);
+    VoxelAabb::from_world(center - reach, center + reach)
+  }
+
+  /// Works out the new density of every voxel the brush changes around `center`, as one
+  /// batch for `ChunkManager::write_voxels` however many chunks it spans. Voxels that
+  /// aren't loaded are left alone.
+  pub fn stroke(&self, chunk_manager: &ChunkManager, center: Vector3<f32>) -> Vec<(VoxelPos, f32)> {
+    let bounds = self.bounds(center);
+    // every density is read before any is written, so smoothing sees the old values
+    let before = bounds.expand(1).voxels()
+      .filter_map(|voxel| chunk_manager.density(voxel).map(|density| (voxel, density)))
+      .collect::<HashMap<_, _>>();
+    let noise = match self.kind {
+      BrushKind::Roughen { frequency, seed } => Some(roughen_noise(bounds, frequency, seed)),
+      _ => None,
+    };
+
+    let mut writes = Vec::new();
+    for voxel in bounds.voxels() {
+      let density = match before.get(&voxel) {
+        Some(density) => *density,
+        None => continue,
+      };
+      let offset = voxel.to_world() - center;
+      let t = match self.kind {
+        BrushKind::Carve => offset.abs().max() / self.radius,
+        _ => offset.norm() / self.radius,
+      };
+      let weight = self.falloff.weight(t) * self.strength;
+      if weight <= 0.0 {
+        continue;
+      }
+
+      let lerp = |target: f32| density + (target - density) * weight.min(1.0);
+      let value = match self.kind {
+        BrushKind::Add => density + weight,
+        BrushKind::Subtract | BrushKind::Carve => density - weight,
+        BrushKind::Smooth => lerp(neighbourhood_average(&before, voxel).unwrap_or(density)),
+        // solid below the plane, in the same units as the generator's densities
+        BrushKind::Flatten { normal } => {
+          lerp(-offset.dot(&normal.try_normalize(1e-6).unwrap_or_else(Vector3::y)))
+        }
+        BrushKind::Roughen { .. } => {
+          let noise = noise.as_ref().unwrap();
+          density + noise[&voxel] * weight
+        }
+      };
+      writes.push((voxel, value));
+    }
+    writes
+  }
+
+  /// strokes the brush and sends the result as a single `ChunkEvent::WriteBatch`
+  pub fn apply(&self, chunk_manager: &ChunkManager, center: Vector3<f32>, events: &mut EventChannel<ChunkEvent>) {
+    let writes = self.stroke(chunk_manager, center);
+    if !writes.is_empty() {
+      events.single_write(ChunkEvent::WriteBatch(writes));
+    }
+  }
+}
+
+fn neighbourhood_average(densities: &HashMap<VoxelPos, f32>, voxel: VoxelPos) -> Option<f32> {
+  let neighbourhood = VoxelAabb::new(voxel, voxel).expand(1);
+  let (sum, count) = neighbourhood.voxels()
+    .filter_map(|neighbour| densities.get(&neighbour))
+    .fold((0.0, 0), |(sum, count), density| (sum + density, count + 1));
+  if count > 0 {
+    Some(sum / count as f32)
+  } else {
+    None
+  }
+}
+
+/// noise in -1..1 for every voxel of the box, sampled in world space
+fn roughen_noise(bounds: VoxelAabb, frequency: f32, seed: i32) -> HashMap<VoxelPos, f32> {
+  let min: Vector3<i32> = bounds.min.into();
+  let size: Vector3<i32> = Vector3::from(bounds.max) - min + Vector3::repeat(1);
+  let (noise, _, _) = NoiseBuilder::fbm_3d_offset(
+    min.x as f32, size.x as usize,
+    min.y as f32, size.y as usize,
+    min.z as f32, size.z as usize,
+  )
+    .with_seed(seed)
+    .with_freq(frequency)
+    .generate();
+
+  // x varies fastest, then y, then z
+  bounds.voxels()
+    .map(|voxel| {
+      let local = Vector3::from(voxel) - min;
+      let i = ((local.z * size.y + local.y) * size.x + local.x) as usize;
+      (voxel, noise[i].max(-1.0).min(1.0))
+    })
+    .collect()
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+use std::sync::{Mutex, atomic::{AtomicU64, Ordering}};
+use dashmap::DashMap;
+use crate::config::terrain::ChunkBudget;
+use super::coord::ChunkPos;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChunkStats {
+  /// requests served by an already loaded chunk
+  pub hits: u64,
+  /// requests that had to load or generate the chunk
+  pub misses: u64,
+  pub evictions: u64,
+}
+
+/// Bookkeeping for keeping `ChunkManager` within its budget: when each chunk
+/// was last used, which chunks are in view and must stay, and the counters.
+pub struct ChunkCache {
+  budget: ChunkBudget,
+  clock: AtomicU64,
+  last_used: DashMap<ChunkPos, u64>,
+  in_view: Mutex<HashSet<ChunkPos>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  evictions: AtomicU64,
+}
+
+impl ChunkCache {
+  pub fn new(budget: ChunkBudget) -> ChunkCache {
+    ChunkCache {
+      budget,
+      clock: AtomicU64::new(0),
+      last_used: DashMap::new(),
+      in_view: Mutex::new(HashSet::new()),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      evictions: AtomicU64::new(0),
+    }
+  }
+
+  pub fn touch(&self, chunk_pos: ChunkPos) {
+    let now = self.clock.fetch_add(1, Ordering::Relaxed);
+    self.last_used.insert(chunk_pos, now);
+  }
+
+  pub fn forget(&self, chunk_pos: ChunkPos) {
+    self.last_used.remove(&chunk_pos);
+  }
+
+  pub fn record_hit(&self) {
+    self.hits.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_miss(&self) {
+    self.misses.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_eviction(&self) {
+    self.evictions.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Replaces the set of chunks that may not be evicted. Chunks in view are in use
+  /// every frame, so they are all stamped as used now, and are the last to go once they leave.
+  pub fn set_view(&self, view: HashSet<ChunkPos>) {
+    let now = self.clock.fetch_add(1, Ordering::Relaxed);
+    for chunk_pos in &view {
+      self.last_used.insert(*chunk_pos, now);
+    }
+    *self.in_view.lock().unwrap() = view;
+  }
+
+  /// Given every loaded chunk and its size in bytes, picks the chunks to drop to get
+  /// back within budget, least recently used first. Chunks in view are never picked,
+  /// so this can come up short if the view alone exceeds the budget.
+  pub fn eviction_candidates(&self, loaded: &[(ChunkPos, usize)]) -> Vec<ChunkPos> {
+    let cost = |size: usize| match self.budget {
+      ChunkBudget::Chunks(_) => 1,
+      ChunkBudget::Bytes(_) => size,
+    };
+    let limit = match self.budget {
+      ChunkBudget::Chunks(limit) | ChunkBudget::Bytes(limit) => limit,
+    };
+    let mut usage: usize = loaded.iter().map(|(_, size)| cost(*size)).sum();
+    if usage <= limit {
+      return Vec::new();
+    }
+
+    let in_view = self.in_view.lock().unwrap();
+    let mut candidates = loaded.iter()
+      .filter(|(pos, _)| !in_view.contains(pos))
+      .map(|&(pos, size)| {
+        let last_used = self.last_used.get(&pos).map(|t| *t).unwrap_or(0);
+        (last_used, pos, size)
+      })
+      .collect::<Vec<_>>();
+    candidates.sort_by_key(|(last_used, _, _)| *last_used);
+
+    let mut evict = Vec::new();
+    for (_, pos, size) in candidates {
+      if usage <= limit {
+        break;
+      }
+      usage -= cost(size);
+      evict.push(pos);
+    }
+    evict
+  }
+
+  pub fn stats(&self) -> ChunkStats {
+    ChunkStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+    }
+  }
+}
@@ -1,97 +1,469 @@
 use ndarray::Array3;
-use amethyst::{shrev::EventChannel, core::math::{try_convert, Vector3}, renderer::types::MeshData};
-use derive_more::{From, Into};
-use std::convert;
-use super::terrain_gen::TerrainGenerator;
-use dashmap::DashMap;
+use amethyst::{shrev::EventChannel, core::math::Vector3, renderer::types::MeshData};
+use super::surface_net::SurfaceNet;
+use super::storage::{DensityStorage, MaterialStorage};
+use super::material::VoxelMaterial;
+use super::constant::CHUNK_PADDING;
+use super::coord::{ChunkPos, VoxelPos, VoxelAabb};
+use super::worker::{ChunkWorkers, ChunkSource, CancelFlag, SaveWorker};
+use super::cache::{ChunkCache, ChunkStats};
+use crate::config::terrain::{DensityFormat, TerrainConfig};
+use dashmap::{DashMap, ElementGuard};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::io;
 
 // The idea is to have chunk manager load chunks via ChunkSystem
 // Then we regenerate the meshes as required
-#[derive(From, Into, Copy, Clone, Debug, Eq, PartialEq, Hash)]
-pub struct VoxelPos(Vector3<i32>);
-
-#[derive(From, Into, Copy, Clone, Debug, Eq, PartialEq, Hash)]
-pub struct ChunkPos(Vector3<i32>);
+#[derive(Clone, Debug)]
+pub struct Chunk {
+  pos: ChunkPos,
+  data: DensityStorage,
+  materials: MaterialStorage,
+}
 
-#[derive(From, Into, Copy, Clone, Debug, Eq, PartialEq, Hash)]
-pub struct LocalPos(Vector3<u8>);
+impl Chunk {
+  pub fn new(pos: ChunkPos, data: Array3<f32>, materials: &Array3<VoxelMaterial>) -> Self {
+    Self { pos, data: DensityStorage::Dense(data), materials: MaterialStorage::new(materials) }
+  }
 
-impl VoxelPos {
-  pub fn as_local(self, pos: ChunkPos) -> LocalPos {
-    // unwrap should succeed because the value ought to fit
-    LocalPos(try_convert(self.0 - VoxelPos::from(pos).0).unwrap())
+  pub fn from_storage(pos: ChunkPos, data: DensityStorage, materials: MaterialStorage) -> Self {
+    Self { pos, data, materials }
   }
-}
 
-impl LocalPos {
-  pub fn as_voxel(self, pos: ChunkPos) -> VoxelPos {
-    let local_pos : Vector3<i32> = convert(self.0);
-    VoxelPos(local_pos + VoxelPos::from(pos).0)
+  /// re-encodes the densities if they aren't stored in `format` already
+  pub fn with_format(self, format: DensityFormat) -> Self {
+    if self.data.format() == format {
+      return self;
+    }
+    let data = DensityStorage::new(self.data.densities().into_owned(), format);
+    Self { pos: self.pos, data, materials: self.materials }
   }
-}
 
-impl convert::From<ChunkPos> for VoxelPos {
-  fn from(pos: ChunkPos) -> Self {
-    VoxelPos(pos.0 * CHUNK_LEN_I32)
+  pub fn pos(&self) -> ChunkPos {
+    self.pos
   }
-}
 
-impl convert::From<VoxelPos> for ChunkPos {
-  fn from(pos: VoxelPos) -> Self {
-    ChunkPos(pos.0 / CHUNK_LEN_I32)
+  pub fn data(&self) -> &DensityStorage {
+    &self.data
   }
-}
 
-#[derive(Clone, Debug)]
-pub struct Chunk {
-  pos: ChunkPos,
-  data: Array3<f32>,
-}
+  pub fn materials(&self) -> &MaterialStorage {
+    &self.materials
+  }
 
-impl Chunk {
-  pub fn new(pos: ChunkPos, data: Array3<f32>) -> Self { Self { pos, data } }
+  /// every density as a float, decoded if the chunk is compressed
+  pub fn densities(&self) -> Cow<'_, Array3<f32>> {
+    self.data.densities()
+  }
 
+  /// None if this chunk holds no copy of the voxel
   pub fn get(&self, idx: VoxelPos) -> Option<f32> {
-    let idx: Vector3<usize> = convert(idx.as_local(self.pos).0);
-    self.data.get(idx.into()).copied()
+    let local = idx.as_local(self.pos)?;
+    self.get_local(local.as_index())
+  }
+
+  /// samples by index into the padded data, which is what the mesher walks over
+  pub fn get_local(&self, idx: (usize, usize, usize)) -> Option<f32> {
+    self.data.get(idx)
+  }
+
+  /// None if this chunk holds no copy of the voxel
+  pub fn material(&self, idx: VoxelPos) -> Option<VoxelMaterial> {
+    let local = idx.as_local(self.pos)?;
+    self.materials.get(local.as_index())
+  }
+
+  pub fn material_local(&self, idx: (usize, usize, usize)) -> Option<VoxelMaterial> {
+    self.materials.get(idx)
+  }
+
+  /// overwrites the density at `idx`, returns false if this chunk holds no copy of it
+  pub fn set(&mut self, idx: VoxelPos, value: f32) -> bool {
+    self.write(&[(idx, value)]) > 0
+  }
+
+  /// applies a batch of writes, skipping voxels this chunk holds no copy of,
+  /// and returns how many were applied
+  pub fn write(&mut self, writes: &[(VoxelPos, f32)]) -> usize {
+    let pos = self.pos;
+    let local_writes = writes.iter()
+      .filter_map(|(idx, value)| idx.as_local(pos).map(|local| (local.as_index(), *value)))
+      .collect::<Vec<_>>();
+    self.data.write(&local_writes)
   }
 
-  pub fn iter(&self) -> impl Iterator<Item = (VoxelPos, f32)> {
-    self.data.indexed_iter().map(|((x, y, z), v)| {
-      let loc: Vector3<i32> = convert(Vector3::new(x, y, z));
-      (VoxelPos::from(loc), *v)
+  /// like `write`, for materials
+  pub fn paint(&mut self, writes: &[(VoxelPos, VoxelMaterial)]) -> usize {
+    let pos = self.pos;
+    let local_writes = writes.iter()
+      .filter_map(|(idx, material)| idx.as_local(pos).map(|local| (local.as_index(), *material)))
+      .collect::<Vec<_>>();
+    self.materials.write(&local_writes)
+  }
+
+  /// every stored voxel, padding included, by world voxel position
+  pub fn iter(&self) -> impl Iterator<Item = (VoxelPos, f32)> + '_ {
+    let origin = self.pos.origin();
+    let (_, dy, dz) = self.data.dim();
+    self.data.iter().enumerate().map(move |(i, v)| {
+      let loc = Vector3::new((i / (dy * dz)) as i32, ((i / dz) % dy) as i32, (i % dz) as i32);
+      (origin.offset(loc), v)
     })
   }
+
+  /// rough heap footprint of the densities and materials
+  pub fn size_bytes(&self) -> usize {
+    self.data.size_bytes() + self.materials.size_bytes()
+  }
 }
 
 pub struct ChunkState {
   chunk: Chunk,
-  mesh: MeshData,
+  mesh: Option<MeshData>,
+  /// comes from the generator, so edits leave it alone
+  water: Option<MeshData>,
+  /// written to since the mesh was last built
+  dirty: bool,
+  /// written to since it was last saved
+  modified: bool,
 }
 
+impl ChunkState {
+  fn new(chunk: Chunk, mesh: Option<MeshData>, water: Option<MeshData>) -> Self {
+    ChunkState { chunk, mesh, water, dirty: false, modified: false }
+  }
+
+  pub fn chunk(&self) -> &Chunk {
+    &self.chunk
+  }
+
+  /// None if the chunk has no surface in it
+  pub fn mesh(&self) -> Option<&MeshData> {
+    self.mesh.as_ref()
+  }
+
+  /// None if there is no water surface in the chunk
+  pub fn water_mesh(&self) -> Option<&MeshData> {
+    self.water.as_ref()
+  }
+
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  pub fn is_modified(&self) -> bool {
+    self.modified
+  }
+}
+
+#[derive(Clone, Debug)]
 pub enum ChunkEvent {
-  Write(Vector3<i32>, f32)
+  /// writes a voxel density at the given world voxel position
+  Write(Vector3<i32>, f32),
+  /// writes many densities at once, e.g. a whole brush stroke
+  WriteBatch(Vec<(VoxelPos, f32)>),
+  /// takes back the last batch, or run of `Write`s, through the `EditHistory`
+  Undo,
+  /// reapplies the last edit that was undone
+  Redo,
+}
+
+/// Chunks store their padding as a copy of the neighbouring voxels, so a voxel
+/// lives in its owning chunk and in each neighbour whose padding reaches it.
+pub fn chunks_containing(voxel: VoxelPos) -> impl Iterator<Item = ChunkPos> {
+  let min = voxel.offset(Vector3::repeat(-(CHUNK_PADDING as i32)));
+  VoxelAabb::new(min, voxel).chunks()
 }
 
 pub struct ChunkManager {
   loaded: DashMap<ChunkPos, ChunkState>,
-  terrain_gen: TerrainGenerator,
-  surface_net: SurfaceNet,
+  pending: DashMap<ChunkPos, CancelFlag>,
+  source: Arc<ChunkSource>,
+  surface_net: Arc<SurfaceNet>,
+  workers: ChunkWorkers,
+  saver: SaveWorker,
+  cache: ChunkCache,
 }
 
 impl ChunkManager {
-  pub fn new(terrain_gen: TerrainGenerator, surface_net: SurfaceNet) -> ChunkManager {
+  pub fn new(source: ChunkSource, surface_net: SurfaceNet, config: &TerrainConfig) -> ChunkManager {
+    let source = Arc::new(source);
+    let surface_net = Arc::new(surface_net);
     ChunkManager {
       loaded: DashMap::new(),
-      terrain_gen,
+      pending: DashMap::new(),
+      workers: ChunkWorkers::new(config.worker_threads, source.clone(), surface_net.clone()),
+      saver: SaveWorker::new(source.clone()),
+      source,
       surface_net,
+      cache: ChunkCache::new(config.chunk_budget),
+    }
+  }
+
+  /// loads or generates the chunk and meshes it on the calling thread, unless it is already loaded
+  pub fn load_chunk(&self, chunk_pos: ChunkPos) -> ElementGuard<ChunkPos, ChunkState> {
+    if let Some(state) = self.get_chunk(chunk_pos) {
+      self.cache.record_hit();
+      return state;
     }
+
+    self.cache.record_miss();
+    self.cancel_chunk(chunk_pos);
+    let chunk = self.source.fetch(chunk_pos);
+    let cubes = self.surface_net.mk_cubes(&chunk);
+    let mesh = self.surface_net.mk_mesh(cubes);
+    let water = self.source.water_mesh(chunk_pos);
+    self.insert_loaded(ChunkState::new(chunk, mesh, water));
+    self.get_chunk(chunk_pos).expect("chunk was just loaded")
+  }
+
+  /// queues the chunk on the workers, it shows up in `poll_finished` once meshed.
+  /// Returns true if the chunk is already loaded and nothing was queued
+  pub fn request_chunk(&self, chunk_pos: ChunkPos) -> bool {
+    // only loaded chunks are stamped, `poll_finished` does the rest once they load
+    if self.is_loaded(chunk_pos) {
+      self.cache.touch(chunk_pos);
+      self.cache.record_hit();
+      return true;
+    }
+    if self.is_pending(chunk_pos) {
+      return false;
+    }
+
+    self.cache.record_miss();
+    let cancelled: CancelFlag = Arc::new(AtomicBool::new(false));
+    self.pending.insert(chunk_pos, cancelled.clone());
+    self.workers.submit(chunk_pos, cancelled);
+    false
+  }
+
+  /// stops a queued or running job, whatever it produces is discarded
+  pub fn cancel_chunk(&self, chunk_pos: ChunkPos) {
+    if let Some(cancelled) = self.pending.remove_take(&chunk_pos) {
+      cancelled.store(true, Ordering::Relaxed);
+    }
+    // it may have been stamped while in view, which shouldn't outlive it
+    if !self.is_loaded(chunk_pos) {
+      self.cache.forget(chunk_pos);
+    }
+  }
+
+  /// moves the chunks the workers are done with into `loaded`, returning their positions
+  pub fn poll_finished(&self) -> Vec<ChunkPos> {
+    let mut finished = Vec::new();
+    for job in self.workers.drain() {
+      // a cancelled job may since have been requested again under a new flag
+      let current = self.pending.remove_if(&job.pos, |_, cancelled| Arc::ptr_eq(cancelled, &job.cancelled));
+      if !current {
+        continue;
+      }
+      self.insert_loaded(ChunkState::new(job.chunk, job.mesh, job.water));
+      finished.push(job.pos);
+    }
+    finished
+  }
+
+  /// Puts a fetched chunk into `loaded`, catching it up on writes made to it while it wasn't
+  /// loaded that came in after it was fetched. It's remeshed right away rather than marked
+  /// dirty, since its entity is only spawned once this frame's edits have been remeshed.
+  fn insert_loaded(&self, mut state: ChunkState) {
+    let chunk_pos = state.chunk.pos();
+    if let Some(writes) = self.source.unapplied_writes(chunk_pos) {
+      state.modified = true;
+      let before = writes.iter().map(|(voxel, _)| state.chunk.get(*voxel)).collect::<Vec<_>>();
+      state.chunk.write(&writes);
+      let changed = writes.iter().zip(before).any(|((voxel, _), before)| state.chunk.get(*voxel) != before);
+      if changed {
+        state.mesh = self.surface_net.mk_mesh(self.surface_net.mk_cubes(&state.chunk));
+      }
+    }
+    self.loaded.insert(chunk_pos, state);
+    self.cache.touch(chunk_pos);
+  }
+
+  /// Applies the writes to every loaded chunk holding a copy of the voxels and marks them dirty.
+  /// Other chunks holding a copy get the writes saved on the `SaveWorker`, so their padding
+  /// still matches the voxels' owners once they load.
+  pub fn write_voxels(&self, writes: &[(VoxelPos, f32)]) {
+    let mut by_chunk: HashMap<ChunkPos, Vec<(VoxelPos, f32)>> = HashMap::new();
+    for &(voxel, value) in writes {
+      for chunk_pos in chunks_containing(voxel) {
+        by_chunk.entry(chunk_pos).or_default().push((voxel, value));
+      }
+    }
+
+    for (chunk_pos, writes) in by_chunk {
+      if !self.is_loaded(chunk_pos) {
+        self.saver.write(chunk_pos, &writes);
+        continue;
+      }
+      // loaded states are immutable, so each touched chunk is copied once per batch
+      self.loaded.update(&chunk_pos, |_, state| {
+        let mut chunk = state.chunk.clone();
+        chunk.write(&writes);
+        ChunkState { chunk, mesh: state.mesh.clone(), water: state.water.clone(), dirty: true, modified: true }
+      });
+    }
+  }
+
+  /// rebuilds the meshes of the dirty chunks, returning their positions
+  pub fn remesh_dirty(&self) -> Vec<ChunkPos> {
+    let dirty = self.loaded.iter()
+      .filter(|state| state.dirty)
+      .map(|state| *state.key())
+      .collect::<Vec<_>>();
+    for chunk_pos in &dirty {
+      self.loaded.update(chunk_pos, |_, state| {
+        let mesh = self.surface_net.mk_mesh(self.surface_net.mk_cubes(&state.chunk));
+        ChunkState { chunk: state.chunk.clone(), mesh, water: state.water.clone(), dirty: false, modified: state.modified }
+      });
+    }
+    dirty
+  }
+
+  /// drops the chunk from the manager, queueing a save on the `SaveWorker` if it was
+  /// modified, and hands back its last state
+  pub fn unload_chunk(&self, chunk_pos: ChunkPos) -> Option<ElementGuard<ChunkPos, ChunkState>> {
+    self.cancel_chunk(chunk_pos);
+    self.cache.forget(chunk_pos);
+    let state = self.loaded.remove_take(&chunk_pos)?;
+    if state.modified {
+      self.saver.save(state.chunk.clone());
+    }
+    Some(state)
+  }
+
+  /// marks the chunks currently in view, which are exempt from eviction
+  pub fn set_view(&self, view: HashSet<ChunkPos>) {
+    self.cache.set_view(view);
+  }
+
+  /// unloads least recently used chunks out of view until the manager is within budget,
+  /// saving the modified ones first. Returns how many were evicted
+  pub fn evict_over_budget(&self) -> usize {
+    let loaded = self.loaded.iter()
+      .map(|state| (*state.key(), state.chunk.size_bytes()))
+      .collect::<Vec<_>>();
+    let evict = self.cache.eviction_candidates(&loaded);
+    for chunk_pos in &evict {
+      self.unload_chunk(*chunk_pos);
+      self.cache.record_eviction();
+    }
+    evict.len()
+  }
+
+  pub fn stats(&self) -> ChunkStats {
+    self.cache.stats()
+  }
+
+  /// saves every modified chunk, e.g. on shutdown, along with the evicted ones still waiting on the `SaveWorker`
+  pub fn save_all(&self) -> io::Result<()> {
+    for chunk_pos in self.source.unsaved_chunks() {
+      self.source.flush(chunk_pos)?;
+    }
+    let modified = self.loaded.iter()
+      .filter(|state| state.modified)
+      .collect::<Vec<_>>();
+    self.source.save(modified.iter().map(|state| &state.chunk))?;
+    for state in modified {
+      self.loaded.update(state.key(), |_, state| ChunkState {
+        chunk: state.chunk.clone(),
+        mesh: state.mesh.clone(),
+        water: state.water.clone(),
+        dirty: state.dirty,
+        modified: false,
+      });
+    }
+    Ok(())
+  }
+
+  /// Surface height of the generated terrain at (x, z), without loading anything.
+  /// Edits are left out, `surface_height` reads them from the loaded chunks.
+  pub fn generated_surface_height(&self, x: f32, z: f32) -> f32 {
+    self.source.terrain_gen.surface_height(x, z)
+  }
+
+  /// Water surface over the voxel column holding (x, z), None if it's dry.
+  /// Water comes from the generator, so edits neither drain nor flood anything.
+  pub fn water_level(&self, x: f32, z: f32) -> Option<f32> {
+    self.source.terrain_gen.water_level(x, z)
+  }
+
+  /// density at a voxel, read from whichever loaded chunk holds a copy of it
+  pub fn density(&self, voxel: VoxelPos) -> Option<f32> {
+    chunks_containing(voxel)
+      .filter_map(|chunk_pos| self.get_chunk(chunk_pos))
+      .find_map(|state| state.chunk.get(voxel))
+  }
+
+  pub fn material(&self, voxel: VoxelPos) -> Option<VoxelMaterial> {
+    chunks_containing(voxel)
+      .filter_map(|chunk_pos| self.get_chunk(chunk_pos))
+      .find_map(|state| state.chunk.material(voxel))
+  }
+
+  pub fn pending_chunks(&self) -> Vec<ChunkPos> {
+    self.pending.iter().map(|entry| *entry.key()).collect()
+  }
+
+  pub fn is_pending(&self, chunk_pos: ChunkPos) -> bool {
+    self.pending.contains_key(&chunk_pos)
+  }
+
+  pub fn get_chunk(&self, chunk_pos: ChunkPos) -> Option<ElementGuard<ChunkPos, ChunkState>> {
+    let state = self.loaded.get(&chunk_pos)?;
+    self.cache.touch(chunk_pos);
+    Some(state)
+  }
+
+  pub fn loaded_chunks(&self) -> Vec<ChunkPos> {
+    self.loaded.iter().map(|state| *state.key()).collect()
+  }
+
+  pub fn is_loaded(&self, chunk_pos: ChunkPos) -> bool {
+    self.loaded.contains_key(&chunk_pos)
+  }
+}
+
+/// Voxels the tests of the chunk encodings share.
+#[cfg(test)]
+pub mod fixture {
+  use std::io;
+  use ndarray::Array3;
+  use crate::config::terrain::DensityFormat;
+  use crate::terrain::coord::ChunkPos;
+  use crate::terrain::material::{VoxelMaterial, MATERIALS};
+  use crate::terrain::terrain_gen::CHUNK_DIM;
+  use super::Chunk;
+
+  /// solid and empty stretches at the bottom and top, in between a slope reaching
+  /// past ±4 on both sides, with exact 0s along x = 9
+  pub fn densities() -> Array3<f32> {
+    Array3::from_shape_fn(CHUNK_DIM, |(x, y, z)| match y {
+      0..=4 => -4.0,
+      15..=18 => 4.0,
+      _ => (x as f32 - 9.0) * (0.6 + z as f32 * 0.01),
+    })
+  }
+
+  /// diagonal bands of every material
+  pub fn materials() -> Array3<VoxelMaterial> {
+    Array3::from_shape_fn(CHUNK_DIM, |(x, y, _)| MATERIALS[(x + y / 4) % MATERIALS.len()])
   }
 
-  pub fn load_chunk(&self, chunk_pos: ChunkPos) {
+  pub fn chunk(pos: ChunkPos, format: DensityFormat) -> Chunk {
+    Chunk::new(pos, densities(), &materials()).with_format(format)
   }
 
-  pub fn get_chunk(&self, chunk_pos: ChunkPos) -> Option<ElementGuard<ChunkState>> {
-    self.loaded.get(&chunk_pos)
+  /// decodes what `encode` writes, checking that all of it is read back
+  pub fn round_trip<T>(encode: impl FnOnce(&mut Vec<u8>), decode: impl FnOnce(&mut &[u8]) -> io::Result<T>) -> T {
+    let mut bytes = Vec::new();
+    encode(&mut bytes);
+    let mut reader = &bytes[..];
+    let decoded = decode(&mut reader).unwrap();
+    assert!(reader.is_empty());
+    decoded
   }
 }
@@ -1,2 +1,4 @@
 pub const CHUNK_LEN: usize = 16;
 pub const CHUNK_LEN_I32: i32 = 16;
+/// samples a chunk stores past its own extent so the mesher can reach into its neighbours
+pub const CHUNK_PADDING: usize = 3;
@@ -0,0 +1,180 @@
+use amethyst::core::math::{convert, Vector3};
+use derive_more::{From, Into};
+use std::convert;
+use super::constant::CHUNK_LEN_I32;
+
+/// Position of a voxel in the world, one unit per voxel.
+#[derive(From, Into, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct VoxelPos(Vector3<i32>);
+
+/// Position of a chunk, in units of `CHUNK_LEN` voxels.
+#[derive(From, Into, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ChunkPos(Vector3<i32>);
+
+/// Position of a voxel relative to the origin of a chunk.
+#[derive(From, Into, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct LocalPos(Vector3<u8>);
+
+impl VoxelPos {
+  pub fn new(x: i32, y: i32, z: i32) -> Self {
+    VoxelPos(Vector3::new(x, y, z))
+  }
+
+  /// the voxel containing the world space point
+  pub fn from_world(point: Vector3<f32>) -> Self {
+    VoxelPos(point.map(|v| v.floor() as i32))
+  }
+
+  /// world space position of the voxel's corner
+  pub fn to_world(self) -> Vector3<f32> {
+    convert(self.0)
+  }
+
+  /// the chunk owning this voxel
+  pub fn chunk(self) -> ChunkPos {
+    ChunkPos(self.0.map(|v| v.div_euclid(CHUNK_LEN_I32)))
+  }
+
+  /// position within the owning chunk, always in `0..CHUNK_LEN`
+  pub fn local(self) -> LocalPos {
+    LocalPos(self.0.map(|v| v.rem_euclid(CHUNK_LEN_I32) as u8))
+  }
+
+  /// position relative to the origin of `pos`, which may lie outside of it, e.g. in its padding.
+  /// None if the voxel is behind the origin or too far out to fit a `LocalPos`
+  pub fn as_local(self, pos: ChunkPos) -> Option<LocalPos> {
+    // nalgebra's try_convert doesn't range check integer casts, so do it by hand
+    let local = self.0 - VoxelPos::from(pos).0;
+    if local.iter().all(|v| 0 <= *v && *v <= u8::MAX as i32) {
+      Some(LocalPos(local.map(|v| v as u8)))
+    } else {
+      None
+    }
+  }
+
+  pub fn offset(self, offset: Vector3<i32>) -> Self {
+    VoxelPos(self.0 + offset)
+  }
+}
+
+impl ChunkPos {
+  pub fn new(x: i32, y: i32, z: i32) -> Self {
+    ChunkPos(Vector3::new(x, y, z))
+  }
+
+  /// the chunk containing the world space point
+  pub fn from_world(point: Vector3<f32>) -> Self {
+    VoxelPos::from_world(point).chunk()
+  }
+
+  /// the voxel at the chunk's minimum corner
+  pub fn origin(self) -> VoxelPos {
+    VoxelPos::from(self)
+  }
+
+  pub fn offset(self, offset: Vector3<i32>) -> Self {
+    ChunkPos(self.0 + offset)
+  }
+}
+
+impl LocalPos {
+  pub fn as_voxel(self, pos: ChunkPos) -> VoxelPos {
+    let local_pos : Vector3<i32> = convert(self.0);
+    VoxelPos(local_pos + VoxelPos::from(pos).0)
+  }
+
+  pub fn as_index(self) -> (usize, usize, usize) {
+    (self.0.x as usize, self.0.y as usize, self.0.z as usize)
+  }
+}
+
+impl convert::From<ChunkPos> for VoxelPos {
+  fn from(pos: ChunkPos) -> Self {
+    VoxelPos(pos.0 * CHUNK_LEN_I32)
+  }
+}
+
+impl convert::From<VoxelPos> for ChunkPos {
+  fn from(pos: VoxelPos) -> Self {
+    pos.chunk()
+  }
+}
+
+/// Inclusive box of voxels.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VoxelAabb {
+  pub min: VoxelPos,
+  pub max: VoxelPos,
+}
+
+impl VoxelAabb {
+  pub fn new(a: VoxelPos, b: VoxelPos) -> Self {
+    VoxelAabb {
+      min: VoxelPos(a.0.zip_map(&b.0, i32::min)),
+      max: VoxelPos(a.0.zip_map(&b.0, i32::max)),
+    }
+  }
+
+  /// every voxel touched by the world space box
+  pub fn from_world(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+    VoxelAabb::new(VoxelPos::from_world(min), VoxelPos::from_world(max))
+  }
+
+  pub fn contains(&self, pos: VoxelPos) -> bool {
+    self.min.0.iter().zip(pos.0.iter()).all(|(min, v)| min <= v)
+      && self.max.0.iter().zip(pos.0.iter()).all(|(max, v)| v <= max)
+  }
+
+  /// grows the box by `amount` voxels on every side
+  pub fn expand(&self, amount: i32) -> Self {
+    let amount = Vector3::repeat(amount);
+    VoxelAabb {
+      min: VoxelPos(self.min.0 - amount),
+      max: VoxelPos(self.max.0 + amount),
+    }
+  }
+
+  pub fn voxels(&self) -> impl Iterator<Item = VoxelPos> {
+    let (min, max) = (self.min.0, self.max.0);
+    (min.x..=max.x).flat_map(move |x| {
+      (min.y..=max.y).flat_map(move |y| {
+        (min.z..=max.z).map(move |z| VoxelPos::new(x, y, z))
+      })
+    })
+  }
+
+  /// the chunks owning at least one voxel of the box
+  pub fn chunks(&self) -> impl Iterator<Item = ChunkPos> {
+    let (min, max) = (self.min.chunk().0, self.max.chunk().0);
+    (min.x..=max.x).flat_map(move |x| {
+      (min.y..=max.y).flat_map(move |y| {
+        (min.z..=max.z).map(move |z| ChunkPos::new(x, y, z))
+      })
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn negative_voxels_belong_to_the_chunk_below() {
+    let voxel = VoxelPos::new(-1, -16, -17);
+    assert_eq!(voxel.chunk(), ChunkPos::new(-1, -1, -2));
+    assert_eq!(voxel.local(), LocalPos::from(Vector3::new(15, 0, 15)));
+    assert_eq!(voxel.as_local(voxel.chunk()), Some(voxel.local()));
+
+    let origin = ChunkPos::new(-1, -1, -2).origin();
+    assert_eq!(origin, VoxelPos::new(-CHUNK_LEN_I32, -CHUNK_LEN_I32, -2 * CHUNK_LEN_I32));
+    assert_eq!(origin.chunk(), ChunkPos::new(-1, -1, -2));
+    assert_eq!(origin.offset(Vector3::new(-1, 0, 0)).chunk(), ChunkPos::new(-2, -1, -2));
+  }
+
+  #[test]
+  fn negative_world_points_round_down() {
+    let voxel = VoxelPos::from_world(Vector3::new(-0.5, 0.5, -16.5));
+    assert_eq!(voxel, VoxelPos::new(-1, 0, -17));
+    assert_eq!(ChunkPos::from_world(Vector3::new(-0.5, 0.5, -16.5)), ChunkPos::new(-1, 0, -2));
+  }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use amethyst::core::math::Vector3;
+use super::chunk::Chunk;
+use super::coord::ChunkPos;
+use super::material::VoxelMaterial;
+use super::storage::{invalid_data, read_counted, read_dim, read_f32, read_u32, read_u8};
+
+/// The voxels of an edited chunk that differ from what the generator makes of it,
+/// padding included. Generation is deterministic, so this is all a save needs to
+/// rebuild the chunk, as long as the `GeneratorConfig` stays the same.
+#[derive(Clone, Debug)]
+pub struct ChunkDelta {
+  pos: ChunkPos,
+  dim: (usize, usize, usize),
+  /// row major index into the padded chunk, with the voxel's density and material
+  voxels: Vec<(u32, f32, VoxelMaterial)>,
+}
+
+impl ChunkDelta {
+  /// Compares voxel by voxel, so both chunks should store their densities in the same format.
+  pub fn between(generated: &Chunk, edited: &Chunk) -> ChunkDelta {
+    debug_assert_eq!(generated.data().dim(), edited.data().dim());
+    let generated_voxels = generated.data().iter().zip(generated.materials().iter());
+    let edited_voxels = edited.data().iter().zip(edited.materials().iter());
+    let voxels = generated_voxels.zip(edited_voxels)
+      .enumerate()
+      .filter(|(_, (before, after))| before != after)
+      .map(|(i, (_, (density, material)))| (i as u32, density, material))
+      .collect();
+    ChunkDelta { pos: edited.pos(), dim: edited.data().dim(), voxels }
+  }
+
+  pub fn pos(&self) -> ChunkPos {
+    self.pos
+  }
+
+  pub fn dim(&self) -> (usize, usize, usize) {
+    self.dim
+  }
+
+  /// how many voxels differ
+  pub fn len(&self) -> usize {
+    self.voxels.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.voxels.is_empty()
+  }
+
+  /// writes the differing voxels over the freshly generated chunk
+  pub fn apply(&self, mut chunk: Chunk) -> Chunk {
+    let origin = chunk.pos().origin();
+    let (_, dy, dz) = self.dim;
+    let voxel = |i: u32| {
+      let i = i as usize;
+      origin.offset(Vector3::new((i / (dy * dz)) as i32, ((i / dz) % dy) as i32, (i % dz) as i32))
+    };
+    let writes = self.voxels.iter()
+      .map(|&(i, density, _)| (voxel(i), density))
+      .collect::<Vec<_>>();
+    let paints = self.voxels.iter()
+      .map(|&(i, _, material)| (voxel(i), material))
+      .collect::<Vec<_>>();
+    chunk.write(&writes);
+    chunk.paint(&paints);
+    chunk
+  }
+
+  /// dimensions 3 * u32 | count u32 | count * (index u32 | density f32 | material id u8)
+  pub fn encode(&self, out: &mut Vec<u8>) {
+    let (x, y, z) = self.dim;
+    for dim in &[x, y, z] {
+      out.extend_from_slice(&(*dim as u32).to_le_bytes());
+    }
+    out.extend_from_slice(&(self.voxels.len() as u32).to_le_bytes());
+    for (i, density, material) in &self.voxels {
+      out.extend_from_slice(&i.to_le_bytes());
+      out.extend_from_slice(&density.to_le_bytes());
+      out.push(material.id());
+    }
+  }
+
+  pub fn decode(pos: ChunkPos, reader: &mut &[u8]) -> io::Result<ChunkDelta> {
+    let dim = read_dim(reader)?;
+    let len = dim.0 * dim.1 * dim.2;
+    // a voxel differs at most once
+    let count = read_u32(reader)? as usize;
+    if count > len {
+      return Err(invalid_data(format!("chunk {:?} delta has {} voxels out of {}", pos, count, len)));
+    }
+    let voxels = read_counted(reader, count, |reader| {
+      let i = read_u32(reader)?;
+      if i as usize >= len {
+        return Err(invalid_data(format!("chunk {:?} delta reaches past its dimensions", pos)));
+      }
+      Ok((i, read_f32(reader)?, VoxelMaterial::from_id(read_u8(reader)?)))
+    })?;
+    Ok(ChunkDelta { pos, dim, voxels })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::terrain::DensityFormat;
+  use crate::terrain::chunk::fixture::{chunk, round_trip};
+
+  fn generated(pos: ChunkPos) -> Chunk {
+    chunk(pos, DensityFormat::I8 { min: -4.0, max: 4.0 })
+  }
+
+  /// carves a hole at the origin and paints across the far padding
+  fn edited(pos: ChunkPos) -> Chunk {
+    let mut chunk = generated(pos);
+    let origin = pos.origin();
+    chunk.write(&[(origin, 4.0), (origin.offset(Vector3::new(1, 2, 3)), -1.5)]);
+    chunk.paint(&[(origin.offset(Vector3::new(18, 18, 18)), VoxelMaterial::Snow)]);
+    chunk
+  }
+
+  fn round_trip_delta(delta: &ChunkDelta) -> ChunkDelta {
+    let decoded = round_trip(|out| delta.encode(out), |reader| ChunkDelta::decode(delta.pos(), reader));
+    assert_eq!(decoded.dim(), delta.dim());
+    assert_eq!(decoded.len(), delta.len());
+    decoded
+  }
+
+  fn assert_same(a: &Chunk, b: &Chunk) {
+    assert_eq!(a.pos(), b.pos());
+    assert!(a.data().iter().eq(b.data().iter()));
+    assert!(a.materials().iter().eq(b.materials().iter()));
+  }
+
+  #[test]
+  fn delta_round_trips_and_rebuilds_the_edit() {
+    let pos = ChunkPos::new(-2, 0, 5);
+    let delta = ChunkDelta::between(&generated(pos), &edited(pos));
+    assert_eq!(delta.len(), 3);
+    let decoded = round_trip_delta(&delta);
+    assert_same(&decoded.apply(generated(pos)), &edited(pos));
+  }
+
+  #[test]
+  fn empty_delta_round_trips() {
+    let pos = ChunkPos::new(0, -1, 0);
+    let delta = ChunkDelta::between(&generated(pos), &generated(pos));
+    assert!(delta.is_empty());
+    let decoded = round_trip_delta(&delta);
+    assert!(decoded.is_empty());
+    assert_same(&decoded.apply(generated(pos)), &generated(pos));
+  }
+
+  #[test]
+  fn indices_past_the_chunk_are_rejected() {
+    let pos = ChunkPos::new(0, 0, 0);
+    let mut bytes = Vec::new();
+    ChunkDelta::between(&generated(pos), &edited(pos)).encode(&mut bytes);
+    // the first voxel's index follows the dimensions and the count
+    bytes[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(ChunkDelta::decode(pos, &mut &bytes[..]).is_err());
+  }
+}
@@ -0,0 +1,93 @@
+use amethyst::{
+  assets::{AssetStorage, Handle, Loader},
+  core::SystemDesc,
+  derive::SystemDesc,
+  ecs::prelude::{Entities, Join, Read, ReadExpect, ReadStorage, System, SystemData, World, WriteExpect, WriteStorage},
+  renderer::types::Mesh,
+  shrev::{EventChannel, ReaderId},
+};
+use super::chunk::{ChunkEvent, ChunkManager};
+use super::coord::VoxelPos;
+use super::history::EditHistory;
+use super::stream::{TerrainChunk, TerrainWater};
+
+/// Applies the `ChunkEvent`s sent this frame through the `EditHistory`, then
+/// remeshes the chunks they touched and swaps the new meshes onto their entities.
+#[derive(SystemDesc)]
+#[system_desc(name(TerrainEditSystemDesc))]
+pub struct TerrainEditSystem {
+  #[system_desc(event_channel_reader)]
+  reader_id: ReaderId<ChunkEvent>,
+}
+
+impl TerrainEditSystem {
+  pub fn new(reader_id: ReaderId<ChunkEvent>) -> Self {
+    TerrainEditSystem { reader_id }
+  }
+}
+
+impl<'s> System<'s> for TerrainEditSystem {
+  type SystemData = (
+    Entities<'s>,
+    Read<'s, EventChannel<ChunkEvent>>,
+    ReadStorage<'s, TerrainChunk>,
+    ReadStorage<'s, TerrainWater>,
+    WriteStorage<'s, Handle<Mesh>>,
+    ReadExpect<'s, ChunkManager>,
+    WriteExpect<'s, EditHistory>,
+    ReadExpect<'s, Loader>,
+    Read<'s, AssetStorage<Mesh>>,
+  );
+
+  fn run(&mut self, (entities, events, chunks, waters, mut meshes, chunk_manager, mut history, loader, mesh_storage): Self::SystemData) {
+    // consecutive single writes make up one edit, each batch is an edit of its own
+    let mut writes = Vec::new();
+    for event in events.read(&mut self.reader_id) {
+      if let ChunkEvent::Write(pos, density) = event {
+        writes.push((VoxelPos::from(*pos), *density));
+        continue;
+      }
+      if !writes.is_empty() {
+        history.write(&chunk_manager, std::mem::take(&mut writes));
+      }
+      match event {
+        ChunkEvent::WriteBatch(batch) => history.write(&chunk_manager, batch.clone()),
+        ChunkEvent::Undo => {
+          history.undo(&chunk_manager);
+        }
+        ChunkEvent::Redo => {
+          history.redo(&chunk_manager);
+        }
+        ChunkEvent::Write(..) => unreachable!(),
+      }
+    }
+    if !writes.is_empty() {
+      history.write(&chunk_manager, writes);
+    }
+
+    let remeshed = chunk_manager.remesh_dirty();
+    if remeshed.is_empty() {
+      return;
+    }
+    // edits leave the water alone
+    for (entity, chunk, _) in (&entities, &chunks, !&waters).join() {
+      if !remeshed.contains(&chunk.pos) {
+        continue;
+      }
+      let state = match chunk_manager.get_chunk(chunk.pos) {
+        Some(state) => state,
+        None => continue,
+      };
+      // the edit may have carved the first surface into the chunk, or removed its last
+      match state.mesh() {
+        Some(mesh_data) => {
+          let mesh_handle = loader.load_from_data(mesh_data.clone(), (), &mesh_storage);
+          meshes.insert(entity, mesh_handle).expect("chunk entity should be alive");
+        }
+        None => {
+          meshes.remove(entity);
+        }
+      }
+    }
+  }
+}
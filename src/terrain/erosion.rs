@@ -0,0 +1,210 @@
+use std::sync::Arc;
+use ndarray::Array2;
+use crate::config::generator::{ErosionStage, HydraulicConfig, ThermalConfig};
+use super::tile_cache::{TileCache, TileKey, TILE};
+
+/// columns simulated around each side of a tile, so droplets can run in from outside it
+const MARGIN: i32 = 48;
+
+/// Erosion of the height map, running the `ErosionStage`s one after the other.
+///
+/// A chunk's columns get eroded along with everything around them: the world is
+/// split into tiles that are simulated with a margin around them, and every column
+/// blends what the 2 by 2 tiles closest to it made of it, like bilinear interpolation.
+/// A column's height then only depends on where it is, so chunk borders always match.
+/// Coarser LOD levels erode a coarser grid of their own and only approximate the chunks.
+pub struct Erosion {
+  stages: Vec<ErosionStage>,
+  seed: i32,
+  /// how far each cached tile raised or lowered its columns
+  tiles: TileCache<Array2<f32>>,
+}
+
+impl Erosion {
+  pub fn new(stages: Vec<ErosionStage>, seed: i32) -> Erosion {
+    Erosion { stages, seed, tiles: TileCache::new(MARGIN) }
+  }
+
+  /// see `TileCache::reserve`, columns blend in the two tiles closest to them along each axis
+  pub fn reserve(&mut self, span: i32, strides: usize) {
+    self.tiles.reserve(span, strides, 2);
+  }
+
+  /// Erodes the height map of columns spaced `stride` voxels apart from (x, z), indexed by (z, x).
+  /// `raw_height` gives the uneroded height of any column by its world position,
+  /// the tiles around the map are simulated from it.
+  pub fn erode(&self, heights: &mut Array2<f32>, x: i32, z: i32, stride: i32, raw_height: impl Fn(i32, i32) -> f32) {
+    let (sx, sz) = (x.div_euclid(stride), z.div_euclid(stride));
+    for ((j, i), height) in heights.indexed_iter_mut() {
+      let (sx, sz) = (sx + i as i32, sz + j as i32);
+      // tiles are centered at (k + 0.5) * TILE, each fading out over a tile's width
+      let (kx, kz) = ((sx - TILE / 2).div_euclid(TILE), (sz - TILE / 2).div_euclid(TILE));
+      let tx = ((sx - TILE / 2).rem_euclid(TILE) as f32 + 0.5) / TILE as f32;
+      let tz = ((sz - TILE / 2).rem_euclid(TILE) as f32 + 0.5) / TILE as f32;
+      for &(dx, dz, weight) in &[(0, 0, (1.0 - tx) * (1.0 - tz)), (1, 0, tx * (1.0 - tz)), (0, 1, (1.0 - tx) * tz), (1, 1, tx * tz)] {
+        let (kx, kz) = (kx + dx, kz + dz);
+        let delta = self.tile(stride, kx, kz, &raw_height);
+        *height += delta[self.tiles.local(kx, kz, sx, sz)] * weight;
+      }
+    }
+  }
+
+  fn tile(&self, stride: i32, kx: i32, kz: i32, raw_height: &impl Fn(i32, i32) -> f32) -> Arc<Array2<f32>> {
+    let key = (stride, kx, kz);
+    self.tiles.get_or_make(key, || self.simulate(key, raw_height))
+  }
+
+  /// how far the stages raise or lower each column of the tile
+  fn simulate(&self, key: TileKey, raw_height: &impl Fn(i32, i32) -> f32) -> Array2<f32> {
+    let (stride, kx, kz) = key;
+    let (x0, z0) = self.tiles.corner(kx, kz);
+    let side = self.tiles.side();
+    let raw = Array2::from_shape_fn((side, side), |(j, i)| {
+      raw_height((x0 + i as i32) * stride, (z0 + j as i32) * stride)
+    });
+    let mut eroded = raw.clone();
+    let mut rng = Rng::new(self.seed, key);
+    for stage in &self.stages {
+      match stage {
+        ErosionStage::Hydraulic(config) => {
+          for _ in 0..config.droplets {
+            run_droplet(config, &mut eroded, stride as f32, &mut rng);
+          }
+        }
+        ErosionStage::Thermal(config) => {
+          for _ in 0..config.iterations {
+            slide_talus(config, &mut eroded, stride as f32);
+          }
+        }
+      }
+    }
+    eroded - raw
+  }
+}
+
+/// Drops water somewhere on the map and lets it run downhill, picking up sediment where it
+/// speeds up and dropping it where it slows down, until it evaporates or leaves the map.
+fn run_droplet(config: &HydraulicConfig, map: &mut Array2<f32>, spacing: f32, rng: &mut Rng) {
+  let max = (map.ncols() - 1) as f32;
+  let (mut x, mut z) = (rng.next_f32() * max, rng.next_f32() * max);
+  let (mut dir_x, mut dir_z) = (0.0, 0.0);
+  let (mut speed, mut water, mut sediment) = (1.0, 1.0, 0.0);
+
+  for _ in 0..config.lifetime {
+    let (height, grad_x, grad_z) = height_and_gradient(map, x, z);
+    dir_x = dir_x * config.inertia - grad_x * (1.0 - config.inertia);
+    dir_z = dir_z * config.inertia - grad_z * (1.0 - config.inertia);
+    let len = (dir_x * dir_x + dir_z * dir_z).sqrt();
+    if len < 1e-6 {
+      break;
+    }
+    dir_x /= len;
+    dir_z /= len;
+
+    let (old_x, old_z) = (x, z);
+    x += dir_x;
+    z += dir_z;
+    if x < 0.0 || z < 0.0 || x >= max || z >= max {
+      break;
+    }
+
+    let dh = height_and_gradient(map, x, z).0 - height;
+    let capacity = (-dh / spacing).max(MIN_SLOPE) * speed * water * config.capacity;
+    if dh > 0.0 || sediment > capacity {
+      // fill the pit it just climbed out of, or shed what it can't carry
+      let deposit = if dh > 0.0 { dh.min(sediment) } else { (sediment - capacity) * config.deposition_rate };
+      sediment -= deposit;
+      spread(map, old_x, old_z, deposit);
+    } else {
+      // never dig deeper than the drop, or the droplet carves holes behind it
+      let erode = ((capacity - sediment) * config.erosion_rate).min(-dh);
+      sediment += erode;
+      spread(map, old_x, old_z, -erode);
+    }
+
+    speed = (speed * speed - dh / spacing * GRAVITY).max(0.0).sqrt();
+    water *= 1.0 - config.evaporation_rate;
+  }
+}
+
+/// Moves a share of whatever rises above the talus slope from every column onto its lower
+/// neighbours, in proportion to how far each of them is below that slope.
+fn slide_talus(config: &ThermalConfig, map: &mut Array2<f32>, spacing: f32) {
+  let max_drop = config.talus_angle.to_radians().tan() * spacing;
+  let (depth, width) = map.dim();
+  let mut moved = Array2::zeros(map.dim());
+  for ((j, i), &height) in map.indexed_iter() {
+    let mut excess = [0.0; 4];
+    for (n, &(dx, dz)) in [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().enumerate() {
+      let (ni, nj) = (i as i32 + dx, j as i32 + dz);
+      if ni >= 0 && nj >= 0 && (ni as usize) < width && (nj as usize) < depth {
+        excess[n] = (height - map[(nj as usize, ni as usize)] - max_drop).max(0.0);
+      }
+    }
+    let total: f32 = excess.iter().sum();
+    let steepest = excess.iter().copied().fold(0.0, f32::max);
+    if total <= 0.0 {
+      continue;
+    }
+    // moving half the excess brings the steepest pair back to the talus slope, more would just move the spike over
+    let amount = config.rate * steepest / 2.0;
+    moved[(j, i)] -= amount;
+    for (n, &(dx, dz)) in [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().enumerate() {
+      if excess[n] > 0.0 {
+        moved[((j as i32 + dz) as usize, (i as i32 + dx) as usize)] += amount * excess[n] / total;
+      }
+    }
+  }
+  *map += &moved;
+}
+
+/// slope a droplet treats flat ground as, so it still carries a little
+const MIN_SLOPE: f32 = 0.01;
+const GRAVITY: f32 = 4.0;
+
+/// bilinear height at (x, z) and its gradient, which is constant within a cell per axis
+fn height_and_gradient(map: &Array2<f32>, x: f32, z: f32) -> (f32, f32, f32) {
+  let (i, j) = (x as usize, z as usize);
+  let (u, v) = (x - i as f32, z - j as f32);
+  let (h00, h10, h01, h11) = (map[(j, i)], map[(j, i + 1)], map[(j + 1, i)], map[(j + 1, i + 1)]);
+  let grad_x = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+  let grad_z = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+  let height = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+  (height, grad_x, grad_z)
+}
+
+/// adds `amount` to the corners of the cell holding (x, z), weighted by how close they are
+fn spread(map: &mut Array2<f32>, x: f32, z: f32, amount: f32) {
+  let (i, j) = (x as usize, z as usize);
+  let (u, v) = (x - i as f32, z - j as f32);
+  map[(j, i)] += amount * (1.0 - u) * (1.0 - v);
+  map[(j, i + 1)] += amount * u * (1.0 - v);
+  map[(j + 1, i)] += amount * (1.0 - u) * v;
+  map[(j + 1, i + 1)] += amount * u * v;
+}
+
+/// splitmix64, so droplets land in the same places for the same seed and tile
+struct Rng(u64);
+
+impl Rng {
+  fn new(seed: i32, (stride, kx, kz): TileKey) -> Rng {
+    let mut rng = Rng(seed as u32 as u64);
+    for v in &[stride, kx, kz] {
+      rng.0 = rng.next_u64() ^ *v as u32 as u64;
+    }
+    rng
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// uniform in 0..1
+  fn next_f32(&mut self) -> f32 {
+    (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+  }
+}
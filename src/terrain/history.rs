@@ -0,0 +1,107 @@
+use std::collections::{HashSet, VecDeque};
+use crate::config::terrain::TerrainConfig;
+use super::chunk::ChunkManager;
+use super::coord::VoxelPos;
+
+/// One stroke or batch of writes, with what the voxels held before it.
+struct Edit {
+  before: Vec<(VoxelPos, f32)>,
+  after: Vec<(VoxelPos, f32)>,
+}
+
+impl Edit {
+  fn size_bytes(&self) -> usize {
+    (self.before.len() + self.after.len()) * std::mem::size_of::<(VoxelPos, f32)>()
+  }
+}
+
+/// Undo and redo stacks for terrain edits. The oldest edits are forgotten once
+/// there are more than `history_depth` of them or they take up more than `history_bytes`.
+pub struct EditHistory {
+  undo: VecDeque<Edit>,
+  redo: Vec<Edit>,
+  max_edits: usize,
+  max_bytes: usize,
+  /// size of everything in `undo` and `redo`
+  bytes: usize,
+}
+
+impl EditHistory {
+  pub fn new(config: &TerrainConfig) -> EditHistory {
+    EditHistory {
+      undo: VecDeque::new(),
+      redo: Vec::new(),
+      max_edits: config.history_depth,
+      max_bytes: config.history_bytes,
+      bytes: 0,
+    }
+  }
+
+  /// Applies the writes as a single undoable edit. Voxels no loaded chunk holds are
+  /// skipped, there would be nothing to undo them to.
+  pub fn write(&mut self, chunk_manager: &ChunkManager, mut writes: Vec<(VoxelPos, f32)>) {
+    let mut seen = HashSet::new();
+    let before = writes.iter()
+      .filter(|(voxel, _)| seen.insert(*voxel))
+      .filter_map(|&(voxel, _)| chunk_manager.density(voxel).map(|density| (voxel, density)))
+      .collect::<Vec<_>>();
+    if before.is_empty() {
+      return;
+    }
+    let loaded = before.iter().map(|(voxel, _)| *voxel).collect::<HashSet<_>>();
+    writes.retain(|(voxel, _)| loaded.contains(voxel));
+    chunk_manager.write_voxels(&writes);
+
+    // a new edit branches off, whatever was undone can't come back
+    for edit in self.redo.drain(..) {
+      self.bytes -= edit.size_bytes();
+    }
+    self.push_undo(Edit { before, after: writes });
+  }
+
+  /// puts the voxels of the last edit back how they were, false if there is nothing to undo
+  pub fn undo(&mut self, chunk_manager: &ChunkManager) -> bool {
+    match self.undo.pop_back() {
+      Some(edit) => {
+        chunk_manager.write_voxels(&edit.before);
+        self.redo.push(edit);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// reapplies the last undone edit, false if there is nothing to redo
+  pub fn redo(&mut self, chunk_manager: &ChunkManager) -> bool {
+    match self.redo.pop() {
+      Some(edit) => {
+        chunk_manager.write_voxels(&edit.after);
+        self.bytes -= edit.size_bytes();
+        self.push_undo(edit);
+        true
+      }
+      None => false,
+    }
+  }
+
+  pub fn can_undo(&self) -> bool {
+    !self.undo.is_empty()
+  }
+
+  pub fn can_redo(&self) -> bool {
+    !self.redo.is_empty()
+  }
+
+  pub fn size_bytes(&self) -> usize {
+    self.bytes
+  }
+
+  fn push_undo(&mut self, edit: Edit) {
+    self.bytes += edit.size_bytes();
+    self.undo.push_back(edit);
+    while self.undo.len() > self.max_edits || (self.bytes > self.max_bytes && !self.undo.is_empty()) {
+      let oldest = self.undo.pop_front().unwrap();
+      self.bytes -= oldest.size_bytes();
+    }
+  }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashSet;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use amethyst::{core::math::Vector3, renderer::types::MeshData};
+use dashmap::DashMap;
+use crate::config::terrain::TerrainConfig;
+use super::constant::CHUNK_LEN_I32;
+use super::coord::{ChunkPos, VoxelPos};
+use super::surface_net::SurfaceNet;
+use super::terrain_gen::TerrainGenerator;
+use super::water::mk_water_mesh;
+use super::worker::{CancelFlag, WorkerPool};
+
+/// A cell of the LOD octree. A cell at `level` spans `2^level` chunks along each
+/// axis and is meshed from a chunk's worth of samples spaced `2^level` voxels apart,
+/// so level 0 cells are plain chunks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct LodPos {
+  pub level: u8,
+  /// in units of the cell's own size
+  pub pos: Vector3<i32>,
+}
+
+impl From<ChunkPos> for LodPos {
+  fn from(chunk_pos: ChunkPos) -> Self {
+    LodPos { level: 0, pos: chunk_pos.into() }
+  }
+}
+
+impl LodPos {
+  /// the cell at `level` holding the world position
+  pub fn from_world(pos: Vector3<f32>, level: u8) -> Self {
+    let chunk: Vector3<i32> = ChunkPos::from_world(pos).into();
+    LodPos { level, pos: chunk.map(|v| v.div_euclid(1 << level)) }
+  }
+
+  /// voxels between samples
+  pub fn stride(self) -> i32 {
+    1 << self.level
+  }
+
+  pub fn origin(self) -> VoxelPos {
+    VoxelPos::from(self.pos * CHUNK_LEN_I32 * self.stride())
+  }
+
+  /// the chunk, if this is a level 0 cell
+  pub fn chunk(self) -> Option<ChunkPos> {
+    if self.level == 0 {
+      Some(ChunkPos::from(self.pos))
+    } else {
+      None
+    }
+  }
+
+  /// the cell one level up holding this one
+  pub fn parent(self) -> LodPos {
+    LodPos { level: self.level + 1, pos: self.pos.map(|v| v.div_euclid(2)) }
+  }
+
+  /// the eight cells one level down that make up this one
+  pub fn children(self) -> Vec<LodPos> {
+    let mut children = Vec::with_capacity(8);
+    for x in 0..2 {
+      for y in 0..2 {
+        for z in 0..2 {
+          children.push(LodPos { level: self.level - 1, pos: self.pos * 2 + Vector3::new(x, y, z) });
+        }
+      }
+    }
+    children
+  }
+}
+
+/// Cells to draw around the camera. Every level sees `view_distance` of its own cells
+/// across and `vertical_view_distance` up and down, so the range doubles with each
+/// level. A cell is swapped for its children whenever they are all in range at their
+/// level, which leaves full resolution chunks near the camera and coarser cells further out.
+pub fn select(camera: Vector3<f32>, config: &TerrainConfig) -> HashSet<LodPos> {
+  let radius = config.view_distance as i32;
+  let height = config.vertical_view_distance as i32;
+  let in_range = |cell: LodPos| {
+    let d = cell.pos - LodPos::from_world(camera, cell.level).pos;
+    d.x * d.x + d.z * d.z <= radius * radius && d.y.abs() <= height
+  };
+
+  let mut selected = HashSet::new();
+  let mut open = Vec::new();
+  let center = LodPos::from_world(camera, config.lod_levels);
+  for dx in -radius..=radius {
+    for dy in -height..=height {
+      for dz in -radius..=radius {
+        let cell = LodPos { level: center.level, pos: center.pos + Vector3::new(dx, dy, dz) };
+        if in_range(cell) {
+          open.push(cell);
+        }
+      }
+    }
+  }
+
+  while let Some(cell) = open.pop() {
+    if cell.level > 0 {
+      let children = cell.children();
+      if children.iter().all(|child| in_range(*child)) {
+        open.extend(children);
+        continue;
+      }
+    }
+    selected.insert(cell);
+  }
+  selected
+}
+
+struct LodJob {
+  pos: LodPos,
+  cancelled: CancelFlag,
+}
+
+struct FinishedLod {
+  pos: LodPos,
+  cancelled: CancelFlag,
+  mesh: Option<MeshData>,
+  water: Option<MeshData>,
+}
+
+/// Meshes the coarse cells, level 1 and up, in the background. They are sampled
+/// straight from the generator, so edits only show up once the chunks are close
+/// enough to be loaded at full resolution.
+pub struct LodManager {
+  pending: DashMap<LodPos, CancelFlag>,
+  workers: WorkerPool<LodJob, FinishedLod>,
+}
+
+impl LodManager {
+  pub fn new(terrain_gen: TerrainGenerator, surface_net: SurfaceNet, config: &TerrainConfig) -> LodManager {
+    let workers = WorkerPool::new("lod worker", config.worker_threads, move |job: LodJob| {
+      if job.cancelled.load(Ordering::Relaxed) {
+        return None;
+      }
+      let (data, materials) = terrain_gen.generate(job.pos.origin(), job.pos.stride());
+      let cubes = surface_net.mk_cubes_from(&data, |idx| materials[idx]);
+      let mesh = surface_net.mk_lod_mesh(cubes, job.pos.stride() as f32);
+      let water = mk_water_mesh(&terrain_gen, job.pos.origin(), job.pos.stride());
+      Some(FinishedLod { pos: job.pos, cancelled: job.cancelled, mesh, water })
+    });
+    LodManager { pending: DashMap::new(), workers }
+  }
+
+  /// queues the cell's mesh, it shows up in `poll_finished` once done
+  pub fn request(&self, pos: LodPos) {
+    if self.pending.contains_key(&pos) {
+      return;
+    }
+    let cancelled: CancelFlag = Arc::new(AtomicBool::new(false));
+    self.pending.insert(pos, cancelled.clone());
+    self.workers.submit(LodJob { pos, cancelled });
+  }
+
+  pub fn cancel(&self, pos: LodPos) {
+    if let Some(cancelled) = self.pending.remove_take(&pos) {
+      cancelled.store(true, Ordering::Relaxed);
+    }
+  }
+
+  pub fn is_pending(&self, pos: LodPos) -> bool {
+    self.pending.contains_key(&pos)
+  }
+
+  pub fn pending(&self) -> Vec<LodPos> {
+    self.pending.iter().map(|entry| *entry.key()).collect()
+  }
+
+  /// terrain and water meshes finished since the last call, minus the ones cancelled
+  /// in the meantime. Cells without any surface or water come back without those meshes
+  pub fn poll_finished(&self) -> Vec<(LodPos, Option<MeshData>, Option<MeshData>)> {
+    self.workers.drain().into_iter()
+      .filter(|job| {
+        !job.cancelled.load(Ordering::Relaxed)
+          && self.pending.remove_if(&job.pos, |_, flag| Arc::ptr_eq(flag, &job.cancelled))
+      })
+      .map(|job| (job.pos, job.mesh, job.water))
+      .collect()
+  }
+}
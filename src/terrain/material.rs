@@ -0,0 +1,60 @@
+use serde::{Serialize, Deserialize};
+use amethyst::renderer::rendy::mesh::TexCoord;
+
+/// What a voxel is made of. Stored as its id, one byte per voxel.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Deserialize, Serialize)]
+#[repr(u8)]
+pub enum VoxelMaterial {
+  Rock = 0,
+  Dirt = 1,
+  Grass = 2,
+  Sand = 3,
+  Snow = 4,
+}
+
+/// materials in id order, which is also their order in the terrain palette texture
+pub const MATERIALS: [VoxelMaterial; 5] = [
+  VoxelMaterial::Rock,
+  VoxelMaterial::Dirt,
+  VoxelMaterial::Grass,
+  VoxelMaterial::Sand,
+  VoxelMaterial::Snow,
+];
+
+impl Default for VoxelMaterial {
+  fn default() -> Self {
+    VoxelMaterial::Rock
+  }
+}
+
+impl VoxelMaterial {
+  pub fn id(self) -> u8 {
+    self as u8
+  }
+
+  /// unknown ids, e.g. from a newer save, fall back to rock
+  pub fn from_id(id: u8) -> VoxelMaterial {
+    MATERIALS.get(id as usize).copied().unwrap_or_default()
+  }
+
+  /// linear rgba of the material's texel in the palette
+  pub fn color(self) -> [f32; 4] {
+    match self {
+      VoxelMaterial::Rock => [0.35, 0.35, 0.37, 1.0],
+      VoxelMaterial::Dirt => [0.36, 0.24, 0.13, 1.0],
+      VoxelMaterial::Grass => [0.22, 0.45, 0.12, 1.0],
+      VoxelMaterial::Sand => [0.86, 0.78, 0.52, 1.0],
+      VoxelMaterial::Snow => [0.92, 0.94, 0.97, 1.0],
+    }
+  }
+
+  /// Mesh vertices carry their material as a texture coordinate pointing at the
+  /// center of its texel in the palette, one texel per material along u.
+  pub fn tex_coord(self) -> TexCoord {
+    TexCoord([(self.id() as f32 + 0.5) / MATERIALS.len() as f32, 0.5])
+  }
+
+  pub fn from_tex_coord(tex_coord: TexCoord) -> VoxelMaterial {
+    VoxelMaterial::from_id((tex_coord.0[0] * MATERIALS.len() as f32) as u8)
+  }
+}
@@ -0,0 +1,64 @@
+use simdnoise::{scalar, CellDistanceFunction, CellReturnType};
+use crate::config::generator::{NoiseNode, WarpConfig};
+
+/// added to the generator's seed for the two offsets of the warp
+const WARP_X_SEED: i32 = 2000;
+const WARP_Z_SEED: i32 = 2001;
+
+impl NoiseNode {
+  /// Evaluates the tree at the world column (x, z). One point at a time rather
+  /// than a block of noise like `NoiseBuilder`, since warps move every sample somewhere else.
+  pub fn sample(&self, seed: i32, x: f32, z: f32) -> f32 {
+    match self {
+      NoiseNode::Constant(value) => *value,
+      // the scalar functions are only unsafe to match the signatures of the simd ones
+      NoiseNode::Fbm(f) => unsafe { scalar::fbm_2d(x * f.freq, z * f.freq, f.lacunarity, f.gain, f.octaves, seed + f.seed) },
+      NoiseNode::Ridge(f) => unsafe { scalar::ridge_2d(x * f.freq, z * f.freq, f.lacunarity, f.gain, f.octaves, seed + f.seed) },
+      NoiseNode::Turbulence(f) => unsafe { scalar::turbulence_2d(x * f.freq, z * f.freq, f.lacunarity, f.gain, f.octaves, seed + f.seed) },
+      NoiseNode::Cellular { freq, jitter, seed: offset } => unsafe {
+        scalar::cellular_2d(x * freq, z * freq, CellDistanceFunction::Euclidean, CellReturnType::Distance, *jitter, seed + offset)
+      },
+
+      NoiseNode::Add(inputs) => inputs.iter().map(|input| input.sample(seed, x, z)).sum(),
+      NoiseNode::Multiply(inputs) => inputs.iter().map(|input| input.sample(seed, x, z)).product(),
+      NoiseNode::Min(inputs) => inputs.iter().map(|input| input.sample(seed, x, z)).fold(std::f32::INFINITY, f32::min),
+      NoiseNode::Max(inputs) => inputs.iter().map(|input| input.sample(seed, x, z)).fold(std::f32::NEG_INFINITY, f32::max),
+      NoiseNode::Clamp { input, min, max } => input.sample(seed, x, z).max(*min).min(*max),
+      NoiseNode::Curve { input, points } => curve(points, input.sample(seed, x, z)),
+      NoiseNode::Warp { input, x: warp_x, z: warp_z, strength } => {
+        let (dx, dz) = (warp_x.sample(seed, x, z), warp_z.sample(seed, x, z));
+        input.sample(seed, x + dx * strength, z + dz * strength)
+      }
+    }
+  }
+}
+
+/// Where the column at (x, z) samples its noise once warped.
+pub fn warp(config: &WarpConfig, seed: i32, x: f32, z: f32) -> (f32, f32) {
+  let offset = |seed: i32| unsafe {
+    scalar::fbm_2d(x * config.freq, z * config.freq, 2.0, 0.5, config.octaves, seed)
+  };
+  (x + offset(seed + WARP_X_SEED) * config.strength, z + offset(seed + WARP_Z_SEED) * config.strength)
+}
+
+/// Fractal noise at (x, y, z), with the same lacunarity and gain as the warp's.
+/// Like `NoiseNode::sample` it's one point at a time, for the volume to warp along with the columns.
+pub fn fbm_3d(x: f32, y: f32, z: f32, octaves: u8, seed: i32) -> f32 {
+  unsafe { scalar::fbm_3d(x, y, z, 2.0, 0.5, octaves, seed) }
+}
+
+/// like `fbm_3d`, with ridges where the noise crosses zero
+pub fn ridge_3d(x: f32, y: f32, z: f32, octaves: u8, seed: i32) -> f32 {
+  unsafe { scalar::ridge_3d(x, y, z, 2.0, 0.5, octaves, seed) }
+}
+
+fn curve(points: &[(f32, f32)], value: f32) -> f32 {
+  match points.iter().position(|(input, _)| *input > value) {
+    None => points.last().map_or(value, |(_, output)| *output),
+    Some(0) => points[0].1,
+    Some(i) => {
+      let ((x0, y0), (x1, y1)) = (points[i - 1], points[i]);
+      y0 + (y1 - y0) * (value - x0) / (x1 - x0)
+    }
+  }
+}
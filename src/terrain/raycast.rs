@@ -0,0 +1,77 @@
+use amethyst::core::math::Vector3;
+use super::chunk::ChunkManager;
+use super::coord::VoxelPos;
+use super::sample::{trilinear, gradient};
+
+/// iterations of false position used to pin down the crossing inside a cell
+const REFINE_STEPS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RaycastHit {
+  /// minimum corner of the cell the surface was crossed in
+  pub voxel: VoxelPos,
+  /// where the ray meets the interpolated density
+  pub point: Vector3<f32>,
+  /// outward surface normal at `point`
+  pub normal: Vector3<f32>,
+  pub distance: f32,
+}
+
+impl ChunkManager {
+  /// Walks the ray cell by cell (grid DDA) and reports where it first goes from
+  /// empty to solid density. Cells without loaded data are skipped over.
+  pub fn raycast(&self, origin: Vector3<f32>, direction: Vector3<f32>, max_distance: f32) -> Option<RaycastHit> {
+    let dir = direction.try_normalize(1e-6)?;
+    let mut cell = VoxelPos::from_world(origin);
+    let start: Vector3<i32> = cell.into();
+
+    let step = dir.map(|v| if v > 0.0 { 1 } else if v < 0.0 { -1 } else { 0 });
+    let t_delta = dir.map(|v| if v != 0.0 { 1.0 / v.abs() } else { std::f32::INFINITY });
+    let mut t_max = Vector3::zeros();
+    for i in 0..3 {
+      t_max[i] = if dir[i] > 0.0 {
+        (start[i] as f32 + 1.0 - origin[i]) / dir[i]
+      } else if dir[i] < 0.0 {
+        (start[i] as f32 - origin[i]) / dir[i]
+      } else {
+        std::f32::INFINITY
+      };
+    }
+
+    let mut t = 0.0;
+    while t <= max_distance {
+      let t_exit = t_max.min().min(max_distance);
+      if let Some(corners) = self.cell_corners(cell) {
+        let corner = cell.to_world();
+        let density_at = |t: f32| trilinear(&corners, origin + dir * t - corner);
+        let (mut t0, mut t1) = (t, t_exit);
+        let (mut d0, mut d1) = (density_at(t0), density_at(t1));
+        if d0 <= 0.0 && d1 > 0.0 {
+          let mut t_hit = t0;
+          for _ in 0..REFINE_STEPS {
+            t_hit = t0 + (t1 - t0) * d0 / (d0 - d1);
+            let d = density_at(t_hit);
+            if d > 0.0 {
+              t1 = t_hit;
+              d1 = d;
+            } else {
+              t0 = t_hit;
+              d0 = d;
+            }
+          }
+          let point = origin + dir * t_hit;
+          let normal = (-gradient(&corners, point - corner)).try_normalize(1e-6).unwrap_or(-dir);
+          return Some(RaycastHit { voxel: cell, point, normal, distance: t_hit });
+        }
+      }
+
+      let axis = t_max.imin();
+      let mut offset = Vector3::zeros();
+      offset[axis] = step[axis];
+      cell = cell.offset(offset);
+      t = t_max[axis];
+      t_max[axis] += t_delta[axis];
+    }
+    None
+  }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use amethyst::core::math::Vector3;
+use super::chunk::Chunk;
+use super::coord::ChunkPos;
+use super::delta::ChunkDelta;
+use super::storage::{read_counted, DensityStorage, MaterialStorage};
+
+/// chunks per region along each axis
+pub const REGION_LEN: i32 = 16;
+
+const REGION_MAGIC: &[u8; 4] = b"MRGN";
+/// bump whenever the layout changes, regions of any other version are refused
+pub const REGION_VERSION: u32 = 1;
+
+const FULL_CHUNK: u8 = 0;
+const DELTA_CHUNK: u8 = 1;
+
+/// What a region holds for a chunk.
+pub enum SavedChunk {
+  /// every voxel, for chunks edited so heavily the delta would take up more space
+  Full(Chunk),
+  /// only the voxels that differ from the generated chunk
+  Delta(ChunkDelta),
+}
+
+/// Position of a region, in units of `REGION_LEN` chunks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct RegionPos(Vector3<i32>);
+
+impl From<ChunkPos> for RegionPos {
+  fn from(pos: ChunkPos) -> Self {
+    let pos: Vector3<i32> = pos.into();
+    RegionPos(pos.map(|v| v.div_euclid(REGION_LEN)))
+  }
+}
+
+/// Stores the edits of modified chunks on disk, grouped into one file per region.
+///
+/// A region file is laid out as
+/// ```text
+/// magic "MRGN" | version u32 | generator fingerprint u64 | count u32
+/// count * (chunk x, y, z: i32 | offset u64 | len u32)
+/// chunk payloads, each a tag byte followed by a whole chunk (0) or a `ChunkDelta` (1)
+/// ```
+/// with every number little endian.
+///
+/// Deltas saved with a different `GeneratorConfig` would be applied to different
+/// terrain, so they are ignored, and dropped the next time their region is written.
+pub struct RegionStore {
+  dir: PathBuf,
+  /// `GeneratorConfig::fingerprint` of the generator the chunks are diffed against
+  generator: u64,
+  // region files are rewritten whole, so readers and writers take turns
+  lock: Mutex<()>,
+}
+
+impl RegionStore {
+  pub fn new(dir: impl Into<PathBuf>, generator: u64) -> RegionStore {
+    RegionStore { dir: dir.into(), generator, lock: Mutex::new(()) }
+  }
+
+  fn region_path(&self, region: RegionPos) -> PathBuf {
+    self.dir.join(format!("r.{}.{}.{}.region", region.0.x, region.0.y, region.0.z))
+  }
+
+  /// the saved chunk, or None if it was never saved
+  pub fn load_chunk(&self, chunk_pos: ChunkPos) -> io::Result<Option<SavedChunk>> {
+    let _guard = self.lock.lock().unwrap();
+    let path = self.region_path(RegionPos::from(chunk_pos));
+    let mut file = match File::open(&path) {
+      Ok(file) => BufReader::new(file),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    };
+
+    let (generator, index) = read_index(&mut file)?;
+    match index.get(&chunk_pos) {
+      Some(&(offset, len)) => {
+        let payload = read_payload(&mut file, offset, len)?;
+        match decode_chunk(chunk_pos, &payload)? {
+          SavedChunk::Delta(_) if generator != self.generator => {
+            Err(invalid_data(format!("chunk {:?} was saved with a different generator config", chunk_pos)))
+          }
+          saved => Ok(Some(saved)),
+        }
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Writes the chunks out with their deltas against the generated chunks, replacing any
+  /// earlier save of them. Each is saved as whichever of the two takes up less space.
+  pub fn save_chunks<'a>(&self, chunks: impl IntoIterator<Item = (&'a Chunk, ChunkDelta)>) -> io::Result<()> {
+    let mut by_region: HashMap<RegionPos, Vec<(ChunkPos, Vec<u8>)>> = HashMap::new();
+    for (chunk, delta) in chunks {
+      let (full, delta) = (encode_full_chunk(chunk), encode_delta(&delta));
+      let payload = if full.len() < delta.len() { full } else { delta };
+      by_region.entry(RegionPos::from(chunk.pos())).or_default().push((chunk.pos(), payload));
+    }
+
+    let _guard = self.lock.lock().unwrap();
+    fs::create_dir_all(&self.dir)?;
+    for (region, saved) in by_region {
+      let path = self.region_path(region);
+      let mut payloads = read_payloads(&path, self.generator)?;
+      payloads.extend(saved);
+
+      // write next to the old file and swap it in, so a crash never leaves half a region
+      let tmp_path = path.with_extension("region.tmp");
+      write_region(&tmp_path, self.generator, &payloads)?;
+      fs::rename(&tmp_path, &path)?;
+    }
+    Ok(())
+  }
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+  let mut buf = [0; 1];
+  reader.read_exact(&mut buf)?;
+  Ok(buf[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+  let mut buf = [0; 4];
+  reader.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+  let mut buf = [0; 4];
+  reader.read_exact(&mut buf)?;
+  Ok(i32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+  let mut buf = [0; 8];
+  reader.read_exact(&mut buf)?;
+  Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// offset and length of every chunk payload in a region
+type RegionIndex = HashMap<ChunkPos, (u64, u32)>;
+
+/// the fingerprint of the generator the region's deltas were made against, and its index
+fn read_index(reader: &mut impl Read) -> io::Result<(u64, RegionIndex)> {
+  let mut magic = [0; 4];
+  reader.read_exact(&mut magic)?;
+  if &magic != REGION_MAGIC {
+    return Err(invalid_data("not a region file".to_string()));
+  }
+  let version = read_u32(reader)?;
+  if version != REGION_VERSION {
+    return Err(invalid_data(format!("unsupported region version {}", version)));
+  }
+  let generator = read_u64(reader)?;
+
+  let count = read_u32(reader)? as usize;
+  let index = read_counted(reader, count, |reader| {
+    let pos = ChunkPos::new(read_i32(reader)?, read_i32(reader)?, read_i32(reader)?);
+    Ok((pos, (read_u64(reader)?, read_u32(reader)?)))
+  })?;
+  Ok((generator, index.into_iter().collect()))
+}
+
+/// Every payload in the region. Deltas made against another generator than `generator`
+/// are left out, with the file backed up first.
+fn read_payloads(path: &Path, generator: u64) -> io::Result<HashMap<ChunkPos, Vec<u8>>> {
+  let mut file = match File::open(path) {
+    Ok(file) => BufReader::new(file),
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+    Err(e) => return Err(e),
+  };
+
+  let (saved_with, index) = read_index(&mut file)?;
+  let stale = saved_with != generator;
+  if stale {
+    let backup = path.with_extension("region.bak");
+    eprintln!("{:?} was saved with a different generator config, dropping the edits it holds as deltas, the old file is kept as {:?}", path, backup);
+    fs::copy(path, &backup)?;
+  }
+
+  let mut payloads = HashMap::with_capacity(index.len());
+  for (pos, (offset, len)) in index {
+    let payload = read_payload(&mut file, offset, len)?;
+    if stale && payload.first() == Some(&DELTA_CHUNK) {
+      continue;
+    }
+    payloads.insert(pos, payload);
+  }
+  Ok(payloads)
+}
+
+/// reads the `len` bytes at `offset`
+fn read_payload(file: &mut (impl Read + Seek), offset: u64, len: u32) -> io::Result<Vec<u8>> {
+  file.seek(SeekFrom::Start(offset))?;
+  read_counted(file, len as usize, read_u8)
+}
+
+fn write_region(path: &Path, generator: u64, payloads: &HashMap<ChunkPos, Vec<u8>>) -> io::Result<()> {
+  // magic, version, generator, count, then 3 * i32 + u64 + u32 per index entry
+  const HEADER_LEN: u64 = 20;
+  const ENTRY_LEN: u64 = 24;
+
+  let mut file = BufWriter::new(File::create(path)?);
+  file.write_all(REGION_MAGIC)?;
+  file.write_all(&REGION_VERSION.to_le_bytes())?;
+  file.write_all(&generator.to_le_bytes())?;
+  file.write_all(&(payloads.len() as u32).to_le_bytes())?;
+
+  let entries = payloads.iter().collect::<Vec<_>>();
+  let mut offset = HEADER_LEN + ENTRY_LEN * entries.len() as u64;
+  for (pos, payload) in &entries {
+    let pos: Vector3<i32> = (**pos).into();
+    for v in pos.iter() {
+      file.write_all(&v.to_le_bytes())?;
+    }
+    file.write_all(&offset.to_le_bytes())?;
+    file.write_all(&(payload.len() as u32).to_le_bytes())?;
+    offset += payload.len() as u64;
+  }
+  for (_, payload) in &entries {
+    file.write_all(payload)?;
+  }
+  file.flush()
+}
+
+fn encode_full_chunk(chunk: &Chunk) -> Vec<u8> {
+  let mut payload = vec![FULL_CHUNK];
+  chunk.data().encode(&mut payload);
+  chunk.materials().encode(&mut payload);
+  payload
+}
+
+fn encode_delta(delta: &ChunkDelta) -> Vec<u8> {
+  let mut payload = vec![DELTA_CHUNK];
+  delta.encode(&mut payload);
+  payload
+}
+
+fn decode_chunk(chunk_pos: ChunkPos, mut payload: &[u8]) -> io::Result<SavedChunk> {
+  let reader = &mut payload;
+  let tag = read_u8(reader)?;
+  match tag {
+    FULL_CHUNK => decode_full_chunk(chunk_pos, reader).map(SavedChunk::Full),
+    DELTA_CHUNK => ChunkDelta::decode(chunk_pos, reader).map(SavedChunk::Delta),
+    _ => Err(invalid_data(format!("chunk {:?} has unknown payload tag {}", chunk_pos, tag))),
+  }
+}
+
+fn decode_full_chunk(chunk_pos: ChunkPos, reader: &mut &[u8]) -> io::Result<Chunk> {
+  let data = DensityStorage::decode(reader)?;
+  let materials = MaterialStorage::decode(reader)?;
+  Ok(Chunk::from_storage(chunk_pos, data, materials))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::terrain::DensityFormat;
+  use crate::terrain::chunk::fixture;
+
+  const FORMAT: DensityFormat = DensityFormat::I8 { min: -4.0, max: 4.0 };
+
+  /// an empty directory of the test's own
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("march-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+  }
+
+  fn generated(pos: ChunkPos) -> Chunk {
+    fixture::chunk(pos, FORMAT)
+  }
+
+  /// the generated chunk with `voxels` of its voxels overwritten, which saves whole past a few hundred
+  fn edited(pos: ChunkPos, voxels: usize) -> Chunk {
+    let mut chunk = generated(pos);
+    let writes = chunk.iter().take(voxels).map(|(voxel, _)| (voxel, 0.5)).collect::<Vec<_>>();
+    chunk.write(&writes);
+    chunk
+  }
+
+  fn save(store: &RegionStore, chunks: &[Chunk]) {
+    let deltas = chunks.iter().map(|chunk| (chunk, ChunkDelta::between(&generated(chunk.pos()), chunk)));
+    store.save_chunks(deltas).unwrap();
+  }
+
+  fn load(store: &RegionStore, pos: ChunkPos) -> Option<Chunk> {
+    match store.load_chunk(pos).unwrap()? {
+      SavedChunk::Delta(delta) => Some(delta.apply(generated(pos))),
+      SavedChunk::Full(chunk) => Some(chunk),
+    }
+  }
+
+  fn assert_same(a: &Chunk, b: &Chunk) {
+    assert_eq!(a.pos(), b.pos());
+    assert!(a.data().iter().eq(b.data().iter()));
+    assert!(a.materials().iter().eq(b.materials().iter()));
+  }
+
+  #[test]
+  fn saved_chunks_load_back() {
+    let dir = temp_dir("region-round-trip");
+    let store = RegionStore::new(&dir, 1);
+    // two chunks sharing a region, one saved as a delta and one whole, and one in a region of its own
+    let (light, heavy, apart) = (ChunkPos::new(0, 0, 0), ChunkPos::new(1, 2, 3), ChunkPos::new(-1, 0, 16));
+    let chunks = vec![edited(light, 1), edited(heavy, 2000), edited(apart, 5)];
+    save(&store, &chunks);
+
+    let reopened = RegionStore::new(&dir, 1);
+    assert!(matches!(reopened.load_chunk(light).unwrap(), Some(SavedChunk::Delta(_))));
+    assert!(matches!(reopened.load_chunk(heavy).unwrap(), Some(SavedChunk::Full(_))));
+    for chunk in &chunks {
+      assert_same(&load(&reopened, chunk.pos()).unwrap(), chunk);
+    }
+    assert!(load(&reopened, ChunkPos::new(0, 1, 0)).is_none());
+
+    // saving again replaces the chunk and keeps the rest of its region
+    let again = edited(light, 30);
+    save(&reopened, &[again.clone()]);
+    assert_same(&load(&store, light).unwrap(), &again);
+    assert_same(&load(&store, heavy).unwrap(), &chunks[1]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn deltas_made_with_another_generator_are_dropped() {
+    let dir = temp_dir("region-stale-generator");
+    let (light, heavy, later) = (ChunkPos::new(0, 0, 0), ChunkPos::new(1, 0, 0), ChunkPos::new(2, 0, 0));
+    let chunks = vec![edited(light, 1), edited(heavy, 2000)];
+    save(&RegionStore::new(&dir, 1), &chunks);
+
+    // whole chunks don't depend on the generator, deltas would come out wrong
+    let store = RegionStore::new(&dir, 2);
+    assert!(store.load_chunk(light).is_err());
+    assert_same(&load(&store, heavy).unwrap(), &chunks[1]);
+
+    // writing the region drops the deltas, after backing it up
+    let fresh = edited(later, 1);
+    save(&store, &[fresh.clone()]);
+    assert!(dir.join("r.0.0.0.region.bak").exists());
+    assert!(load(&store, light).is_none());
+    assert_same(&load(&store, heavy).unwrap(), &chunks[1]);
+    assert_same(&load(&store, later).unwrap(), &fresh);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
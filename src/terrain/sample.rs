@@ -0,0 +1,112 @@
+use amethyst::core::math::Vector3;
+use super::chunk::ChunkManager;
+use super::constant::CHUNK_LEN_I32;
+use super::coord::{ChunkPos, VoxelPos};
+
+/// distance either side of the point `sample_gradient` takes its differences over
+const GRADIENT_STEP: f32 = 0.5;
+
+impl ChunkManager {
+  /// Density at any world point, trilinearly interpolated from the voxels around it,
+  /// which may sit in different chunks. None unless all eight of them are loaded.
+  pub fn sample(&self, point: Vector3<f32>) -> Option<f32> {
+    let cell = VoxelPos::from_world(point);
+    let corners = self.cell_corners(cell)?;
+    Some(trilinear(&corners, point - cell.to_world()))
+  }
+
+  /// Central difference gradient of `sample`. It points into the terrain, so the
+  /// outward surface normal is its negation, and its horizontal part gives the slope.
+  pub fn sample_gradient(&self, point: Vector3<f32>) -> Option<Vector3<f32>> {
+    let mut gradient = Vector3::zeros();
+    for i in 0..3 {
+      let mut step = Vector3::zeros();
+      step[i] = GRADIENT_STEP;
+      gradient[i] = (self.sample(point + step)? - self.sample(point - step)?) / (2.0 * GRADIENT_STEP);
+    }
+    Some(gradient)
+  }
+
+  /// whether the point is inside the terrain, None if it isn't loaded
+  pub fn is_solid(&self, point: Vector3<f32>) -> Option<bool> {
+    self.sample(point).map(|density| density > 0.0)
+  }
+
+  /// whether the point is below the sea or a lake without being inside the terrain,
+  /// None if it isn't loaded
+  pub fn is_underwater(&self, point: Vector3<f32>) -> Option<bool> {
+    let solid = self.is_solid(point)?;
+    Some(!solid && self.water_level(point.x, point.z).map_or(false, |level| point.y < level))
+  }
+
+  /// Height of the topmost surface in the column at (x, z), from the loaded and possibly
+  /// edited chunks. Scans down from the top of the highest loaded chunk in the column
+  /// until it goes from empty to solid. None if the column isn't loaded, if it is solid
+  /// right up to the top of the loaded data, or if it runs out of loaded data first.
+  pub fn surface_height(&self, x: f32, z: f32) -> Option<f32> {
+    let column: Vector3<i32> = ChunkPos::from_world(Vector3::new(x, 0.0, z)).into();
+    let top = self.loaded_chunks()
+      .into_iter()
+      .map(Vector3::from)
+      .filter(|pos: &Vector3<i32>| pos.x == column.x && pos.z == column.z)
+      .map(|pos| (pos.y + 1) * CHUNK_LEN_I32 - 1)
+      .max()?;
+
+    let mut above = self.sample(Vector3::new(x, top as f32, z))?;
+    if above > 0.0 {
+      return None;
+    }
+    let mut y = top - 1;
+    loop {
+      let density = self.sample(Vector3::new(x, y as f32, z))?;
+      if density > 0.0 {
+        // linear between the two samples, as the mesh has it
+        return Some(y as f32 + density / (density - above));
+      }
+      above = density;
+      y -= 1;
+    }
+  }
+
+  /// densities at the 8 corners of the cell, ordered like `SurfaceNet`'s corner offsets
+  pub(super) fn cell_corners(&self, cell: VoxelPos) -> Option<[f32; 8]> {
+    let mut corners = [0.0; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+      let offset = Vector3::new((i & 1) as i32, ((i >> 1) & 1) as i32, ((i >> 2) & 1) as i32);
+      *corner = self.density(cell.offset(offset))?;
+    }
+    Some(corners)
+  }
+}
+
+/// density inside a cell, `p` in 0..1 along every axis
+pub(super) fn trilinear(c: &[f32; 8], p: Vector3<f32>) -> f32 {
+  let x00 = c[0] + (c[1] - c[0]) * p.x;
+  let x10 = c[2] + (c[3] - c[2]) * p.x;
+  let x01 = c[4] + (c[5] - c[4]) * p.x;
+  let x11 = c[6] + (c[7] - c[6]) * p.x;
+  let y0 = x00 + (x10 - x00) * p.y;
+  let y1 = x01 + (x11 - x01) * p.y;
+  y0 + (y1 - y0) * p.z
+}
+
+/// analytic gradient of `trilinear`
+pub(super) fn gradient(c: &[f32; 8], p: Vector3<f32>) -> Vector3<f32> {
+  let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+  let dx = lerp(
+    lerp(c[1] - c[0], c[3] - c[2], p.y),
+    lerp(c[5] - c[4], c[7] - c[6], p.y),
+    p.z,
+  );
+  let dy = lerp(
+    lerp(c[2] - c[0], c[3] - c[1], p.x),
+    lerp(c[6] - c[4], c[7] - c[5], p.x),
+    p.z,
+  );
+  let dz = lerp(
+    lerp(c[4] - c[0], c[5] - c[1], p.x),
+    lerp(c[6] - c[2], c[7] - c[3], p.x),
+    p.y,
+  );
+  Vector3::new(dx, dy, dz)
+}
@@ -0,0 +1,480 @@
+use std::borrow::Cow;
+use std::io;
+use ndarray::Array3;
+use crate::config::terrain::DensityFormat;
+use super::material::VoxelMaterial;
+use super::terrain_gen::CHUNK_DIM;
+
+type Idx = (usize, usize, usize);
+
+/// Densities of a chunk, either as plain floats or quantized to a byte and
+/// run-length encoded, which suits the large solid and empty stretches of most chunks.
+#[derive(Clone, Debug)]
+pub enum DensityStorage {
+  Dense(Array3<f32>),
+  Compressed(CompressedDensity),
+}
+
+/// One byte per voxel, stored as runs of equal values.
+#[derive(Clone, Debug)]
+pub struct CompressedDensity {
+  dim: Idx,
+  format: DensityFormat,
+  runs: Vec<Run>,
+}
+
+/// Material of every voxel of a chunk, as runs of equal ids.
+#[derive(Clone, Debug)]
+pub struct MaterialStorage {
+  dim: Idx,
+  runs: Vec<Run>,
+}
+
+/// `end` is the index one past the run, so runs can be binary searched
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Run {
+  end: u32,
+  value: u8,
+}
+
+fn encode_runs(values: impl IntoIterator<Item = u8>) -> Vec<Run> {
+  let mut runs: Vec<Run> = Vec::new();
+  for (i, value) in values.into_iter().enumerate() {
+    match runs.last_mut() {
+      Some(run) if run.value == value => run.end = i as u32 + 1,
+      _ => runs.push(Run { end: i as u32 + 1, value }),
+    }
+  }
+  runs
+}
+
+fn decode_runs(runs: &[Run]) -> Vec<u8> {
+  let mut values = Vec::with_capacity(runs.last().map(|run| run.end as usize).unwrap_or(0));
+  let mut start = 0;
+  for run in runs {
+    values.extend(std::iter::repeat(run.value).take((run.end - start) as usize));
+    start = run.end;
+  }
+  values
+}
+
+fn run_value(runs: &[Run], i: usize) -> Option<u8> {
+  let i = i as u32;
+  // the run containing i is the first one ending past it
+  let run = match runs.binary_search_by_key(&i, |run| run.end) {
+    Ok(found) => found + 1,
+    Err(found) => found,
+  };
+  runs.get(run).map(|run| run.value)
+}
+
+fn iter_runs(runs: &[Run]) -> impl Iterator<Item = u8> + '_ {
+  let starts = std::iter::once(0).chain(runs.iter().map(|run| run.end));
+  starts.zip(runs.iter()).flat_map(|(start, run)| {
+    std::iter::repeat(run.value).take((run.end - start) as usize)
+  })
+}
+
+fn encode_run_list(runs: &[Run], out: &mut Vec<u8>) {
+  out.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+  for run in runs {
+    out.extend_from_slice(&run.end.to_le_bytes());
+    out.push(run.value);
+  }
+}
+
+fn decode_run_list(reader: &mut &[u8], len: usize) -> io::Result<Vec<Run>> {
+  // every run covers at least a voxel
+  let count = read_u32(reader)? as usize;
+  if count > len {
+    return Err(invalid_data(format!("{} runs for {} voxels", count, len)));
+  }
+  let runs = read_counted(reader, count, |reader| Ok(Run { end: read_u32(reader)?, value: read_u8(reader)? }))?;
+  // lookups binary search the ends, and decoding subtracts each from the next
+  let first_empty = runs.first().map(|run| run.end) == Some(0);
+  if first_empty || runs.windows(2).any(|pair| pair[1].end <= pair[0].end) {
+    return Err(invalid_data("runs are out of order".to_string()));
+  }
+  if runs.last().map(|run| run.end as usize) != Some(len) {
+    return Err(invalid_data("runs do not cover the chunk".to_string()));
+  }
+  Ok(runs)
+}
+
+impl DensityFormat {
+  fn quantize(self, value: f32) -> u8 {
+    match self {
+      DensityFormat::Float => panic!("float densities are not quantized"),
+      DensityFormat::U8 { min, max } => {
+        let t = (value.max(min).min(max) - min) / (max - min);
+        (t * 255.0).round() as u8
+      }
+      // symmetric around the middle of the range, so a symmetric range keeps 0 exact
+      DensityFormat::I8 { min, max } => {
+        let mid = (min + max) * 0.5;
+        let half = (max - min) * 0.5;
+        let t = (value.max(min).min(max) - mid) / half;
+        (t * 127.0).round() as i8 as u8
+      }
+    }
+  }
+
+  fn dequantize(self, value: u8) -> f32 {
+    match self {
+      DensityFormat::Float => panic!("float densities are not quantized"),
+      DensityFormat::U8 { min, max } => min + (value as f32 / 255.0) * (max - min),
+      DensityFormat::I8 { min, max } => {
+        let mid = (min + max) * 0.5;
+        let half = (max - min) * 0.5;
+        mid + (value as i8 as f32 / 127.0) * half
+      }
+    }
+  }
+}
+
+fn linear_index(dim: Idx, (x, y, z): Idx) -> Option<usize> {
+  if x < dim.0 && y < dim.1 && z < dim.2 {
+    Some((x * dim.1 + y) * dim.2 + z)
+  } else {
+    None
+  }
+}
+
+impl CompressedDensity {
+  pub fn encode(data: &Array3<f32>, format: DensityFormat) -> Self {
+    let quantized = data.iter().map(|v| format.quantize(*v)).collect::<Vec<_>>();
+    CompressedDensity::from_quantized(data.dim(), format, &quantized)
+  }
+
+  fn from_quantized(dim: Idx, format: DensityFormat, quantized: &[u8]) -> Self {
+    CompressedDensity { dim, format, runs: encode_runs(quantized.iter().copied()) }
+  }
+
+  fn quantized(&self) -> Vec<u8> {
+    decode_runs(&self.runs)
+  }
+
+  pub fn get(&self, idx: Idx) -> Option<f32> {
+    let i = linear_index(self.dim, idx)?;
+    run_value(&self.runs, i).map(|value| self.format.dequantize(value))
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+    iter_runs(&self.runs).map(move |value| self.format.dequantize(value))
+  }
+
+  pub fn decode(&self) -> Array3<f32> {
+    Array3::from_shape_vec(self.dim, self.iter().collect()).unwrap()
+  }
+}
+
+impl DensityStorage {
+  pub fn new(data: Array3<f32>, format: DensityFormat) -> Self {
+    match format {
+      DensityFormat::Float => DensityStorage::Dense(data),
+      _ => DensityStorage::Compressed(CompressedDensity::encode(&data, format)),
+    }
+  }
+
+  pub fn dim(&self) -> Idx {
+    match self {
+      DensityStorage::Dense(data) => data.dim(),
+      DensityStorage::Compressed(compressed) => compressed.dim,
+    }
+  }
+
+  pub fn format(&self) -> DensityFormat {
+    match self {
+      DensityStorage::Dense(_) => DensityFormat::Float,
+      DensityStorage::Compressed(compressed) => compressed.format,
+    }
+  }
+
+  pub fn get(&self, idx: Idx) -> Option<f32> {
+    match self {
+      DensityStorage::Dense(data) => data.get(idx).copied(),
+      DensityStorage::Compressed(compressed) => compressed.get(idx),
+    }
+  }
+
+  /// every density in row major order
+  pub fn iter(&self) -> Box<dyn Iterator<Item = f32> + '_> {
+    match self {
+      DensityStorage::Dense(data) => Box::new(data.iter().copied()),
+      DensityStorage::Compressed(compressed) => Box::new(compressed.iter()),
+    }
+  }
+
+  /// all densities as floats, only decoding when compressed
+  pub fn densities(&self) -> Cow<'_, Array3<f32>> {
+    match self {
+      DensityStorage::Dense(data) => Cow::Borrowed(data),
+      DensityStorage::Compressed(compressed) => Cow::Owned(compressed.decode()),
+    }
+  }
+
+  /// applies a batch of writes, re-encoding compressed data once for the whole batch.
+  /// Writes outside the data are skipped, the number applied is returned
+  pub fn write(&mut self, writes: &[(Idx, f32)]) -> usize {
+    let mut applied = 0;
+    match self {
+      DensityStorage::Dense(data) => {
+        for (idx, value) in writes {
+          if let Some(density) = data.get_mut(*idx) {
+            *density = *value;
+            applied += 1;
+          }
+        }
+      }
+      DensityStorage::Compressed(compressed) => {
+        let mut quantized = compressed.quantized();
+        for (idx, value) in writes {
+          if let Some(i) = linear_index(compressed.dim, *idx) {
+            quantized[i] = compressed.format.quantize(*value);
+            applied += 1;
+          }
+        }
+        if applied > 0 {
+          *compressed = CompressedDensity::from_quantized(compressed.dim, compressed.format, &quantized);
+        }
+      }
+    }
+    applied
+  }
+
+  /// rough heap footprint
+  pub fn size_bytes(&self) -> usize {
+    match self {
+      DensityStorage::Dense(data) => data.len() * std::mem::size_of::<f32>(),
+      DensityStorage::Compressed(compressed) => compressed.runs.len() * std::mem::size_of::<Run>(),
+    }
+  }
+
+  /// Serializes as
+  /// ```text
+  /// format u8 (0 float, 1 i8, 2 u8) | dimensions 3 * u32
+  /// float: densities as f32
+  /// quantized: min f32 | max f32 | run count u32 | runs * (end u32 | value u8)
+  /// ```
+  /// little endian throughout
+  pub fn encode(&self, out: &mut Vec<u8>) {
+    let (tag, range) = match self.format() {
+      DensityFormat::Float => (0u8, None),
+      DensityFormat::I8 { min, max } => (1, Some((min, max))),
+      DensityFormat::U8 { min, max } => (2, Some((min, max))),
+    };
+    out.push(tag);
+    let (x, y, z) = self.dim();
+    for dim in &[x, y, z] {
+      out.extend_from_slice(&(*dim as u32).to_le_bytes());
+    }
+
+    match self {
+      DensityStorage::Dense(data) => {
+        for v in data.iter() {
+          out.extend_from_slice(&v.to_le_bytes());
+        }
+      }
+      DensityStorage::Compressed(compressed) => {
+        let (min, max) = range.unwrap();
+        out.extend_from_slice(&min.to_le_bytes());
+        out.extend_from_slice(&max.to_le_bytes());
+        encode_run_list(&compressed.runs, out);
+      }
+    }
+  }
+
+  /// reads what `encode` wrote, leaving `reader` just past it
+  pub fn decode(reader: &mut &[u8]) -> io::Result<Self> {
+    let tag = read_u8(reader)?;
+    let dim = read_dim(reader)?;
+    let len = dim.0 * dim.1 * dim.2;
+
+    if tag == 0 {
+      let mut values = Vec::with_capacity(len);
+      for _ in 0..len {
+        values.push(read_f32(reader)?);
+      }
+      let data = Array3::from_shape_vec(dim, values).map_err(|e| invalid_data(e.to_string()))?;
+      return Ok(DensityStorage::Dense(data));
+    }
+
+    let (min, max) = (read_f32(reader)?, read_f32(reader)?);
+    let format = match tag {
+      1 => DensityFormat::I8 { min, max },
+      2 => DensityFormat::U8 { min, max },
+      _ => return Err(invalid_data(format!("unknown density format {}", tag))),
+    };
+    let runs = decode_run_list(reader, len)?;
+    Ok(DensityStorage::Compressed(CompressedDensity { dim, format, runs }))
+  }
+}
+
+impl MaterialStorage {
+  pub fn new(materials: &Array3<VoxelMaterial>) -> Self {
+    MaterialStorage { dim: materials.dim(), runs: encode_runs(materials.iter().map(|m| m.id())) }
+  }
+
+  pub fn dim(&self) -> Idx {
+    self.dim
+  }
+
+  pub fn get(&self, idx: Idx) -> Option<VoxelMaterial> {
+    let i = linear_index(self.dim, idx)?;
+    run_value(&self.runs, i).map(VoxelMaterial::from_id)
+  }
+
+  /// every material in row major order
+  pub fn iter(&self) -> impl Iterator<Item = VoxelMaterial> + '_ {
+    iter_runs(&self.runs).map(VoxelMaterial::from_id)
+  }
+
+  /// applies a batch of writes like `DensityStorage::write`
+  pub fn write(&mut self, writes: &[(Idx, VoxelMaterial)]) -> usize {
+    let mut ids = decode_runs(&self.runs);
+    let mut applied = 0;
+    for (idx, material) in writes {
+      if let Some(i) = linear_index(self.dim, *idx) {
+        ids[i] = material.id();
+        applied += 1;
+      }
+    }
+    if applied > 0 {
+      self.runs = encode_runs(ids);
+    }
+    applied
+  }
+
+  pub fn size_bytes(&self) -> usize {
+    self.runs.len() * std::mem::size_of::<Run>()
+  }
+
+  /// dimensions 3 * u32 | run count u32 | runs * (end u32 | material id u8)
+  pub fn encode(&self, out: &mut Vec<u8>) {
+    let (x, y, z) = self.dim;
+    for dim in &[x, y, z] {
+      out.extend_from_slice(&(*dim as u32).to_le_bytes());
+    }
+    encode_run_list(&self.runs, out);
+  }
+
+  pub fn decode(reader: &mut &[u8]) -> io::Result<Self> {
+    let dim = read_dim(reader)?;
+    let runs = decode_run_list(reader, dim.0 * dim.1 * dim.2)?;
+    Ok(MaterialStorage { dim, runs })
+  }
+}
+
+pub(super) fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_bytes<'a>(reader: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+  if reader.len() < len {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "density data is truncated"));
+  }
+  let (bytes, rest) = reader.split_at(len);
+  *reader = rest;
+  Ok(bytes)
+}
+
+/// Reads `count` items with `read`. Counts come from the data being read, so they aren't trusted
+/// to allocate up front: a corrupt one runs out of data to read before it can run out of memory.
+pub(super) fn read_counted<R, T>(reader: &mut R, count: usize, mut read: impl FnMut(&mut R) -> io::Result<T>) -> io::Result<Vec<T>> {
+  let mut items = Vec::new();
+  for _ in 0..count {
+    items.push(read(reader)?);
+  }
+  Ok(items)
+}
+
+pub(super) fn read_u8(reader: &mut &[u8]) -> io::Result<u8> {
+  Ok(read_bytes(reader, 1)?[0])
+}
+
+pub(super) fn read_u32(reader: &mut &[u8]) -> io::Result<u32> {
+  let b = read_bytes(reader, 4)?;
+  Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Dimensions as 3 * u32, which have to be a chunk's. Anything else is corrupt,
+/// and sizing allocations by it could abort.
+pub(super) fn read_dim(reader: &mut &[u8]) -> io::Result<Idx> {
+  let dim = (read_u32(reader)? as usize, read_u32(reader)? as usize, read_u32(reader)? as usize);
+  if dim != CHUNK_DIM {
+    return Err(invalid_data(format!("dimensions {:?} are not a chunk's", dim)));
+  }
+  Ok(dim)
+}
+
+pub(super) fn read_f32(reader: &mut &[u8]) -> io::Result<f32> {
+  let b = read_bytes(reader, 4)?;
+  Ok(f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::terrain::chunk::fixture::{densities, materials, round_trip};
+
+  fn round_trip_densities(storage: &DensityStorage) -> DensityStorage {
+    round_trip(|out| storage.encode(out), DensityStorage::decode)
+  }
+
+  #[test]
+  fn float_densities_round_trip_exactly() {
+    let storage = DensityStorage::new(densities(), DensityFormat::Float);
+    let decoded = round_trip_densities(&storage);
+    assert_eq!(decoded.format(), DensityFormat::Float);
+    assert_eq!(decoded.dim(), CHUNK_DIM);
+    assert!(decoded.iter().eq(densities().iter().copied()));
+  }
+
+  #[test]
+  fn quantized_densities_round_trip_within_a_step() {
+    let formats = [
+      DensityFormat::I8 { min: -4.0, max: 4.0 },
+      DensityFormat::U8 { min: -4.0, max: 4.0 },
+      DensityFormat::U8 { min: -2.0, max: 6.0 },
+    ];
+    for &format in &formats {
+      let (min, max) = match format {
+        DensityFormat::I8 { min, max } | DensityFormat::U8 { min, max } => (min, max),
+        DensityFormat::Float => unreachable!(),
+      };
+      let storage = DensityStorage::new(densities(), format);
+      let decoded = round_trip_densities(&storage);
+      assert_eq!(decoded.format(), format);
+      assert_eq!(decoded.dim(), CHUNK_DIM);
+      assert!(decoded.iter().eq(storage.iter()));
+
+      let step = (max - min) / 254.0;
+      for (value, decoded) in densities().iter().zip(decoded.iter()) {
+        let expected = value.max(min).min(max);
+        assert!((decoded - expected).abs() <= step, "{:?}: {} decoded as {}", format, value, decoded);
+      }
+      // the ends of the range come back exactly
+      for &value in &[min, max] {
+        assert_eq!(format.dequantize(format.quantize(value)), value);
+      }
+    }
+    let symmetric = DensityFormat::I8 { min: -4.0, max: 4.0 };
+    assert_eq!(symmetric.dequantize(symmetric.quantize(0.0)), 0.0);
+  }
+
+  #[test]
+  fn materials_round_trip() {
+    let storage = MaterialStorage::new(&materials());
+    let decoded = round_trip(|out| storage.encode(out), MaterialStorage::decode);
+    assert_eq!(decoded.dim(), CHUNK_DIM);
+    assert!(decoded.iter().eq(materials().iter().copied()));
+  }
+
+  #[test]
+  fn dimensions_other_than_a_chunk_are_rejected() {
+    let storage = DensityStorage::new(Array3::zeros((2, 2, 2)), DensityFormat::Float);
+    let mut bytes = Vec::new();
+    storage.encode(&mut bytes);
+    assert!(DensityStorage::decode(&mut &bytes[..]).is_err());
+  }
+}
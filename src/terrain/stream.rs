@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+use amethyst::{
+  assets::{AssetStorage, Handle, Loader},
+  core::{Transform, SystemDesc, math::{Point3, Vector3}},
+  derive::SystemDesc,
+  ecs::prelude::{Builder, Component, DenseVecStorage, Entities, Join, LazyUpdate, NullStorage, Read, ReadExpect, ReadStorage, System, SystemData, World},
+  renderer::{ActiveCamera, Material, transparent::Transparent, types::{Mesh, MeshData}, visibility::BoundingSphere},
+};
+use crate::config::terrain::TerrainConfig;
+use super::chunk::ChunkManager;
+use super::constant::{CHUNK_LEN, CHUNK_PADDING};
+use super::coord::{ChunkPos, VoxelPos};
+use super::lod::{self, LodManager, LodPos};
+
+/// material every terrain chunk is drawn with
+pub struct TerrainMaterial(pub Handle<Material>);
+
+/// material the water surfaces are drawn with
+pub struct WaterMaterial(pub Handle<Material>);
+
+/// tags the entity holding the mesh of a loaded chunk
+pub struct TerrainChunk {
+  pub pos: ChunkPos,
+}
+
+impl Component for TerrainChunk {
+  type Storage = DenseVecStorage<Self>;
+}
+
+/// tags the entity holding the mesh of a coarse LOD cell
+pub struct TerrainLod {
+  pub pos: LodPos,
+}
+
+impl Component for TerrainLod {
+  type Storage = DenseVecStorage<Self>;
+}
+
+/// Tags the entity holding the water mesh of a chunk or LOD cell, next to its
+/// `TerrainChunk` or `TerrainLod`, so it is despawned along with the cell's terrain.
+#[derive(Default)]
+pub struct TerrainWater;
+
+impl Component for TerrainWater {
+  type Storage = NullStorage<Self>;
+}
+
+/// Keeps the cells `lod::select` picks around the active camera spawned: full
+/// resolution chunks loaded through the `ChunkManager` up close, coarse LOD
+/// meshes further out. Cells that drop out of the selection are despawned once
+/// the cells replacing them have spawned, so no holes open up in between.
+#[derive(SystemDesc, Default)]
+pub struct ChunkStreamSystem;
+
+impl<'s> System<'s> for ChunkStreamSystem {
+  type SystemData = (
+    Entities<'s>,
+    Read<'s, ActiveCamera>,
+    ReadStorage<'s, Transform>,
+    ReadStorage<'s, TerrainChunk>,
+    ReadStorage<'s, TerrainLod>,
+    ReadExpect<'s, ChunkManager>,
+    ReadExpect<'s, LodManager>,
+    ReadExpect<'s, TerrainConfig>,
+    ReadExpect<'s, TerrainMaterial>,
+    ReadExpect<'s, WaterMaterial>,
+    ReadExpect<'s, Loader>,
+    Read<'s, AssetStorage<Mesh>>,
+    Read<'s, LazyUpdate>,
+  );
+
+  fn run(&mut self, (entities, active_camera, transforms, chunks, lods, chunk_manager, lod_manager, config, material, water_material, loader, mesh_storage, lazy): Self::SystemData) {
+    let camera_pos = match active_camera.entity.and_then(|camera| transforms.get(camera)) {
+      Some(transform) => *transform.translation(),
+      None => return,
+    };
+    let selected = lod::select(camera_pos, &config);
+    // cells without a surface still get an entity, so they count as spawned
+    let spawn = |mesh_data: Option<MeshData>, origin: VoxelPos, stride: i32| {
+      let mut builder = lazy.create_entity(&entities);
+      if let Some(mesh_data) = mesh_data {
+        builder = builder.with(loader.load_from_data(mesh_data, (), &mesh_storage));
+      }
+      builder
+        .with(cell_bounds(stride))
+        .with(material.0.clone())
+        .with(Transform::from(origin.to_world()))
+    };
+    // cells without water don't get a water entity at all
+    let spawn_water = |mesh_data: Option<MeshData>, origin: VoxelPos, stride: i32| {
+      mesh_data.map(|mesh_data| {
+        lazy.create_entity(&entities)
+          .with(loader.load_from_data(mesh_data, (), &mesh_storage))
+          .with(cell_bounds(stride))
+          .with(water_material.0.clone())
+          .with(Transparent)
+          .with(TerrainWater)
+          .with(Transform::from(origin.to_world()))
+      })
+    };
+
+    // every cell with an entity, and the entities of the ones no longer selected
+    let mut spawned = HashSet::new();
+    let mut stale = Vec::new();
+    let cells = (&entities, &chunks).join().map(|(entity, chunk)| (entity, LodPos::from(chunk.pos)))
+      .chain((&entities, &lods).join().map(|(entity, lod)| (entity, lod.pos)));
+    for (entity, cell) in cells {
+      spawned.insert(cell);
+      if !selected.contains(&cell) {
+        stale.push((entity, cell));
+      }
+    }
+
+    // full resolution chunks
+    let center = ChunkPos::from_world(camera_pos);
+    let view = selected.iter()
+      .filter_map(|cell| cell.chunk())
+      .collect::<HashSet<_>>();
+
+    // chunks that left view before their workers got to them
+    for chunk_pos in chunk_manager.pending_chunks() {
+      if !view.contains(&chunk_pos) {
+        chunk_manager.cancel_chunk(chunk_pos);
+      }
+    }
+
+    let mut missing = view.iter()
+      .filter(|pos| !spawned.contains(&LodPos::from(**pos)))
+      .copied()
+      .collect::<Vec<_>>();
+    chunk_manager.set_view(view);
+
+    // spawn the cached chunks right away, queue the rest nearest first
+    missing.sort_by_key(|pos| distance_squared(center, *pos));
+    let mut ready = Vec::new();
+    for chunk_pos in missing {
+      if chunk_manager.request_chunk(chunk_pos) {
+        ready.push(chunk_pos);
+      }
+    }
+    ready.extend(chunk_manager.poll_finished());
+
+    for chunk_pos in ready {
+      let (mesh_data, water) = match chunk_manager.get_chunk(chunk_pos) {
+        Some(state) => (state.mesh().cloned(), state.water_mesh().cloned()),
+        None => continue,
+      };
+      spawn(mesh_data, chunk_pos.origin(), 1)
+        .with(TerrainChunk { pos: chunk_pos })
+        .build();
+      if let Some(builder) = spawn_water(water, chunk_pos.origin(), 1) {
+        builder.with(TerrainChunk { pos: chunk_pos }).build();
+      }
+      spawned.insert(LodPos::from(chunk_pos));
+    }
+
+    // the chunks stay cached until evicted, whether or not they're still spawned
+    chunk_manager.evict_over_budget();
+
+    // coarse cells, which are only ever meshed, never kept around
+    for pos in lod_manager.pending() {
+      if !selected.contains(&pos) {
+        lod_manager.cancel(pos);
+      }
+    }
+    // finest first, those are the closest
+    let mut missing = selected.iter()
+      .filter(|cell| cell.level > 0 && !spawned.contains(cell) && !lod_manager.is_pending(**cell))
+      .copied()
+      .collect::<Vec<_>>();
+    missing.sort_by_key(|cell| cell.level);
+    for pos in missing {
+      lod_manager.request(pos);
+    }
+    for (pos, mesh_data, water) in lod_manager.poll_finished() {
+      spawn(mesh_data, pos.origin(), pos.stride())
+        .with(TerrainLod { pos })
+        .build();
+      if let Some(builder) = spawn_water(water, pos.origin(), pos.stride()) {
+        builder.with(TerrainLod { pos }).build();
+      }
+      spawned.insert(pos);
+    }
+
+    // A stale cell overlaps the selected cells that are its ancestors or descendants, and
+    // stays until all of those have spawned. Entities spawned this frame show up along with
+    // the deletions, once the world is maintained.
+    let unspawned = selected.iter()
+      .filter(|cell| !spawned.contains(cell))
+      .copied()
+      .collect::<HashSet<_>>();
+    let mut waiting = HashSet::new();
+    for cell in &unspawned {
+      let mut ancestor = *cell;
+      while ancestor.level < config.lod_levels {
+        ancestor = ancestor.parent();
+        waiting.insert(ancestor);
+      }
+    }
+    for (entity, cell) in stale {
+      let mut ancestor = cell;
+      let mut replaced = !waiting.contains(&cell);
+      while replaced && ancestor.level < config.lod_levels {
+        ancestor = ancestor.parent();
+        replaced = !unspawned.contains(&ancestor);
+      }
+      if replaced {
+        entities.delete(entity).expect("terrain entity should be alive");
+      }
+    }
+  }
+}
+
+/// Bounds the meshes of a chunk or LOD cell sampled every `stride` voxels, around the middle
+/// of the cell relative to its origin. The meshes reach into the padding, so that's included.
+fn cell_bounds(stride: i32) -> BoundingSphere {
+  let half = ((CHUNK_LEN + CHUNK_PADDING) as i32 * stride) as f32 / 2.0;
+  BoundingSphere::new(Point3::new(half, half, half), half * 3.0f32.sqrt())
+}
+
+fn distance_squared(a: ChunkPos, b: ChunkPos) -> i32 {
+  let a: Vector3<i32> = a.into();
+  let b: Vector3<i32> = b.into();
+  (a - b).dot(&(a - b))
+}
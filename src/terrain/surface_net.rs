@@ -10,7 +10,10 @@ use amethyst::renderer::{
 use getset::{Getters};
 use ndarray::Array3;
 use num_traits::identities::Zero;
-use crate::terrain::terrain_gen::{Chunk, WIDTH, DEPTH, HEIGHT};
+use crate::terrain::terrain_gen::{WIDTH, DEPTH, HEIGHT};
+use crate::terrain::chunk::Chunk;
+use crate::terrain::material::VoxelMaterial;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use ron::ser::PrettyConfig;
@@ -23,6 +26,8 @@ pub struct SurfaceNetCube {
   position: Vector3<f32>,
   on_surface: bool,
   corner_mask: u8,
+  /// material of the most solid corner
+  material: VoxelMaterial,
 }
 
 impl Default for SurfaceNetCube {
@@ -31,12 +36,18 @@ impl Default for SurfaceNetCube {
       position: Vector3::zero(),
       on_surface: false,
       corner_mask: 0,
+      material: VoxelMaterial::default(),
     }
   }
 }
 
 type VecTex = (Vector3<f32>, TexCoord);
 
+/// Vertices are snapped to 1/VERTEX_GRID of a voxel within their cube. Adding the
+/// cube's (and chunk's) integer position is then exact, so neighbouring chunks
+/// meshing the same cube land its vertex on the very same float.
+const VERTEX_GRID: f32 = 1024.0;
+
 pub struct SurfaceNet {
   voxel_corner_offsets: [Vector3<usize>; 8],
   voxel_corner_offsets_f32: [Vector3<f32>; 8],
@@ -131,21 +142,32 @@ impl SurfaceNet {
   }
 
   pub fn mk_cubes(&self, chunk: &Chunk) -> Array3<SurfaceNetCube> {
+    self.mk_cubes_from(&chunk.densities(), |idx| chunk.material_local(idx).unwrap_or_default())
+  }
+
+  /// `mk_cubes` over bare densities, for data that isn't a chunk such as the coarse samples of a LOD
+  pub fn mk_cubes_from(&self, data: &Array3<f32>, material: impl Fn((usize, usize, usize)) -> VoxelMaterial) -> Array3<SurfaceNetCube> {
     let mut cubes = Array3::from_elem((WIDTH + 2, HEIGHT + 2, DEPTH + 2), SurfaceNetCube::default());
     for x in 0..WIDTH + 2 {
       for y in 0..HEIGHT + 2 {
         for z in 0..DEPTH + 2 {
           let mut sample = [0f32; 8];
+          let mut solid_corner = (0, 0, 0);
           for i in 0..8 {
             let offset = self.voxel_corner_offsets[i];
-            let value = chunk.get((x + offset.index(0), y + offset.index(1), z + offset.index(2))).unwrap();
+            let corner = (x + offset.index(0), y + offset.index(1), z + offset.index(2));
+            let value = data[corner];
             sample[i] = value;
+            if i == 0 || value > data[solid_corner] {
+              solid_corner = corner;
+            }
           }
-          let SurfaceNetCube { position, corner_mask, on_surface } = self.mk_surface_net_cube(sample);
+          let SurfaceNetCube { position, corner_mask, on_surface, .. } = self.mk_surface_net_cube(sample);
           cubes[(x, y, z)] = SurfaceNetCube {
-            position: position + Vector3::new(x as f32, y as f32, z as f32),
+            position: position.map(|v| (v * VERTEX_GRID).round() / VERTEX_GRID) + Vector3::new(x as f32, y as f32, z as f32),
             corner_mask,
             on_surface,
+            material: material(solid_corner),
           }
         }
       }
@@ -200,10 +222,31 @@ impl SurfaceNet {
       position: vert_pos,
       corner_mask,
       on_surface: true,
+      material: VoxelMaterial::default(),
+    }
+  }
+
+  /// None if the cubes hold no surface, there is nothing to draw then
+  pub fn mk_mesh(&self, cubes: Array3<SurfaceNetCube>) -> Option<MeshData> {
+    let (vertices, tex_coords, triangles) = self.mk_triangles(cubes);
+    Self::build_mesh(vertices, tex_coords, triangles)
+  }
+
+  /// Meshes cubes sampled every `stride` voxels, scaling the mesh back up to voxel units
+  /// and hanging skirts `2 * stride` deep off its borders. The camera is always on the side
+  /// of the finer neighbour, and the skirt fills in wherever this coarser mesh sits above it.
+  pub fn mk_lod_mesh(&self, cubes: Array3<SurfaceNetCube>, stride: f32) -> Option<MeshData> {
+    let (mut vertices, mut tex_coords, mut triangles) = self.mk_triangles(cubes);
+    for vertex in &mut vertices {
+      for v in &mut vertex.0 {
+        *v *= stride;
+      }
     }
+    Self::add_skirts(&mut vertices, &mut tex_coords, &mut triangles, 2.0 * stride);
+    Self::build_mesh(vertices, tex_coords, triangles)
   }
 
-  pub fn mk_mesh(&self, cubes: Array3<SurfaceNetCube>) -> MeshData {
+  fn mk_triangles(&self, cubes: Array3<SurfaceNetCube>) -> (Vec<Position>, Vec<TexCoord>, Vec<u16>) {
     let mut vertices = Vec::<Position>::new();
     let mut tex_coords = Vec::<TexCoord>::new();
 
@@ -223,7 +266,7 @@ impl SurfaceNet {
       while pos[1] < height - 1 {
         pos[0] = 0;
         while pos[0] < width - 1 {
-          let SurfaceNetCube { position, corner_mask, on_surface} =
+          let SurfaceNetCube { position, corner_mask, on_surface, material } =
             *cubes.get((pos[0], pos[1], pos[2])).unwrap();
           if !on_surface {
             pos[0] += 1;
@@ -231,9 +274,10 @@ impl SurfaceNet {
             continue
           }
 
+          // the material rides along in the tex coord, see `VoxelMaterial::tex_coord`
           vertex_buffer[buf_idx] = (
             position,
-            TexCoord([1.0, 0.0])
+            material.tex_coord()
           );
 
           let edge_mask = self.intersection_table[corner_mask as usize];
@@ -251,6 +295,10 @@ impl SurfaceNet {
             if pos[iu] == 0 || pos[iv] == 0 {
               continue
             }
+            // the crossing at 0 along i is the previous chunk's last one, leave that face to it
+            if pos[i] == 0 {
+              continue
+            }
 
             let du = r[iu];
             let dv = r[iv];
@@ -296,15 +344,54 @@ impl SurfaceNet {
 //    let mut file = File::create("surface_net.triangles.ron").unwrap();
 //    write!(file, "{}", ron::ser::to_string_pretty(&triangles, PrettyConfig::default()).unwrap()).unwrap();
 
+    (vertices, tex_coords, triangles)
+  }
+
+  fn build_mesh(vertices: Vec<Position>, tex_coords: Vec<TexCoord>, triangles: Vec<u16>) -> Option<MeshData> {
+    if triangles.is_empty() {
+      return None;
+    }
     let normals = calculate_normals(&vertices, &triangles);
 
-    MeshData(
+    Some(MeshData(
       MeshBuilder::new()
         .with_vertices(vertices)
         .with_vertices(normals)
         .with_vertices(tex_coords)
         .with_indices(triangles)
-    )
+    ))
+  }
+
+  /// A surface net is closed except where it was cut off at the border of its cubes,
+  /// so the edges only one triangle uses are exactly the border. Each one gets a strip
+  /// hanging `depth` below it, wound both ways so it shows from either side.
+  fn add_skirts(vertices: &mut Vec<Position>, tex_coords: &mut Vec<TexCoord>, triangles: &mut Vec<u16>, depth: f32) {
+    // triangles don't share vertices, so edges are matched up by position
+    let key = |i: u16| {
+      let p = vertices[i as usize].0;
+      [p[0].to_bits(), p[1].to_bits(), p[2].to_bits()]
+    };
+    let mut edges: HashMap<_, (u16, u16, usize)> = HashMap::new();
+    for face in triangles.chunks(3) {
+      for &(a, b) in &[(face[0], face[1]), (face[1], face[2]), (face[2], face[0])] {
+        let (ka, kb) = (key(a), key(b));
+        let edge = if ka < kb { (ka, kb) } else { (kb, ka) };
+        edges.entry(edge).or_insert((a, b, 0)).2 += 1;
+      }
+    }
+
+    let down = Vector3::new(0.0, depth, 0.0);
+    for (a, b, uses) in edges.values().copied().collect::<Vec<_>>() {
+      if uses != 1 {
+        continue;
+      }
+      let top_a: VecTex = (Vector3::from(vertices[a as usize].0), tex_coords[a as usize]);
+      let top_b: VecTex = (Vector3::from(vertices[b as usize].0), tex_coords[b as usize]);
+      let bottom_a: VecTex = (top_a.0 - down, top_a.1);
+      let bottom_b: VecTex = (top_b.0 - down, top_b.1);
+      Self::add_quad(top_a, top_b, bottom_b, bottom_a, vertices, tex_coords, triangles);
+      Self::add_quad(top_b, top_a, bottom_a, bottom_b, vertices, tex_coords, triangles);
+    }
   }
 
   fn push_triangle(vectex: VecTex, vertices: &mut Vec<Position>, tex_coords: &mut Vec<TexCoord>, triangles: &mut Vec<u16>) {
@@ -339,3 +426,72 @@ impl SurfaceNet {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use amethyst::core::math::Vector3;
+  use crate::config::generator::GeneratorConfig;
+  use crate::terrain::coord::ChunkPos;
+  use crate::terrain::terrain_gen::TerrainGenerator;
+
+  fn neighbours() -> Vec<(ChunkPos, ChunkPos)> {
+    let mut pairs = Vec::new();
+    for &a in &[ChunkPos::new(0, 0, 0), ChunkPos::new(-1, 1, -2), ChunkPos::new(3, 0, -1)] {
+      for i in 0..3 {
+        let mut step = Vector3::zeros();
+        step[i] = 1;
+        pairs.push((a, a.offset(step)));
+      }
+    }
+    pairs
+  }
+
+  #[test]
+  fn neighbours_generate_the_same_shared_voxels() {
+    let generator = TerrainGenerator::new(GeneratorConfig::default());
+    for (a, b) in neighbours() {
+      let (a, b) = (generator.generate_chunk(a), generator.generate_chunk(b));
+      let mut shared = 0;
+      for (voxel, density) in b.iter() {
+        if let Some(other) = a.get(voxel) {
+          assert_eq!(density, other, "density at {:?} differs between {:?} and {:?}", voxel, a.pos(), b.pos());
+          assert_eq!(b.material(voxel), a.material(voxel));
+          shared += 1;
+        }
+      }
+      assert!(shared > 0);
+    }
+  }
+
+  #[test]
+  fn boundary_vertices_of_neighbours_coincide() {
+    let generator = TerrainGenerator::new(GeneratorConfig::default());
+    let surface_net = SurfaceNet::new();
+    let mut on_boundary = 0;
+    for (a, b) in neighbours() {
+      let cubes_a = surface_net.mk_cubes(&generator.generate_chunk(a));
+      let cubes_b = surface_net.mk_cubes(&generator.generate_chunk(b));
+      let shift: Vector3<i32> = b.origin().offset(-Vector3::from(a.origin())).into();
+
+      // every cube of b that a holds too
+      for ((x, y, z), cube_b) in cubes_b.indexed_iter() {
+        let in_a = Vector3::new(x as i32, y as i32, z as i32) + shift;
+        let cube_a = match cubes_a.get((in_a.x as usize, in_a.y as usize, in_a.z as usize)) {
+          Some(cube) if in_a.min() >= 0 => cube,
+          _ => continue,
+        };
+        assert_eq!(cube_a.on_surface, cube_b.on_surface);
+        if cube_b.on_surface {
+          // in a's frame, where both meshes put the same float arithmetic behind it
+          assert_eq!(cube_a.position, cube_b.position + shift.map(|v| v as f32),
+                     "vertex of cube {:?} moved between {:?} and {:?}", in_a, a, b);
+          assert_eq!(cube_a.material, cube_b.material);
+          on_boundary += 1;
+        }
+      }
+    }
+    // the pairs straddle the surface, so this isn't passing vacuously
+    assert!(on_boundary > 0);
+  }
+}
@@ -1,44 +1,213 @@
 use ndarray::{Array3, Shape, Array2};
-use simdnoise::NoiseBuilder;
-use crate::config::generator::GeneratorConfig;
+use amethyst::core::math::Vector3;
+use crate::config::generator::{BiomeConfig, DensityMode, GeneratorConfig};
+use crate::config::terrain::TerrainConfig;
+use super::{biome, noise};
+use super::erosion::Erosion;
+use super::water::Lakes;
+use super::chunk::Chunk;
+use super::coord::{ChunkPos, VoxelPos};
+use super::material::VoxelMaterial;
+use super::constant::{CHUNK_LEN, CHUNK_PADDING};
+
+pub const WIDTH: usize = CHUNK_LEN;
+pub const DEPTH: usize = CHUNK_LEN;
+pub const HEIGHT: usize = CHUNK_LEN;
+/// shape of the density array of every generated chunk, padding included
+pub const CHUNK_DIM: (usize, usize, usize) = (WIDTH + CHUNK_PADDING, HEIGHT + CHUNK_PADDING, DEPTH + CHUNK_PADDING);
 
 pub struct TerrainGenerator {
   config: GeneratorConfig,
+  erosion: Option<Erosion>,
+  lakes: Option<Lakes>,
 }
 
 impl TerrainGenerator {
   pub fn new(config: GeneratorConfig) -> TerrainGenerator {
-    TerrainGenerator { config }
-  }
-
-  pub fn generate_chunk(&self, x: i32, z: i32) -> Chunk {
-    let (data, min, max) =
-    // NoiseBuilder::ridge_2d_offset((x * WIDTH as i32) as f32, WIDTH + 1, (x * DEPTH as i32) as f32, DEPTH + 1)
-      NoiseBuilder::ridge_2d_offset((64 * x) as f32, WIDTH + 3, (64 * z) as f32, DEPTH + 3)
-      .with_seed(self.config.seed)
-      .with_lacunarity(self.config.lacunarity)
-      .with_freq(self.config.freq)
-      .with_gain(self.config.gain)
-      .with_octaves(self.config.octaves)
-      .generate();
-    let data = data.into_iter().map(|v| v * self.config.scaling).collect::<Vec<_>>();
-    let height_map = Array2::from_shape_vec((DEPTH + 3, WIDTH + 3), data).unwrap();
-    let data = Array3::from_shape_fn((WIDTH + 3, HEIGHT + 3, DEPTH + 3), |(x, y, z)| {
-      let height: f32 = *height_map.get((z, x)).unwrap();
-      if height.floor() as usize > y {
-        1.0
+    assert!(!config.biomes.is_empty(), "the generator needs at least one biome");
+    let erosion = if config.erosion.is_empty() {
+      None
+    } else {
+      Some(Erosion::new(config.erosion.clone(), config.seed))
+    };
+    let lakes = config.lakes.clone().map(Lakes::new);
+    TerrainGenerator { config, erosion, lakes }
+  }
+
+  /// Sizes the erosion and lake tile caches to hold every tile the view around the camera needs
+  /// at the first `levels` LOD levels, so tiles aren't simulated over again as the camera moves.
+  pub fn with_view(mut self, view: &TerrainConfig, levels: usize) -> Self {
+    // every level spans as many of its own samples across, see `lod::select`
+    let mut span = (2 * view.view_distance as i32 + 1) * CHUNK_LEN as i32;
+    if let Some(lakes) = &mut self.lakes {
+      lakes.reserve(span, levels);
+      // lakes are filled from heights further out than the view
+      span = lakes.reach(span);
+    }
+    if let Some(erosion) = &mut self.erosion {
+      erosion.reserve(span, levels);
+    }
+    self
+  }
+
+  pub fn generate_chunk(&self, chunk_pos: ChunkPos) -> Chunk {
+    let (data, materials) = self.generate(chunk_pos.origin(), 1);
+    Chunk::new(chunk_pos, data, &materials)
+  }
+
+  /// Densities and materials of a chunk's worth of samples taken every `stride` voxels
+  /// from `origin`, which has to be a multiple of the stride.
+  pub fn generate(&self, origin: VoxelPos, stride: i32) -> (Array3<f32>, Array3<VoxelMaterial>) {
+    let origin: Vector3<i32> = origin.into();
+    let volume = &self.config.volume;
+    let (height_map, biomes) = self.height_map(origin.x, origin.z, WIDTH + CHUNK_PADDING, DEPTH + CHUNK_PADDING, stride);
+    let warped = Array2::from_shape_fn((DEPTH + CHUNK_PADDING, WIDTH + CHUNK_PADDING), |(j, i)| {
+      self.warp(origin.x + i as i32 * stride, origin.z + j as i32 * stride)
+    });
+    let noise = match self.config.mode {
+      DensityMode::HeightMap => None,
+      _ => Some(self.noise_3d(&warped, origin.y, stride, volume.freq, |x, y, z| noise::fbm_3d(x, y, z, volume.octaves, self.config.seed))),
+    };
+    let caves = if volume.cave_threshold < 1.0 {
+      Some(self.noise_3d(&warped, origin.y, stride, volume.cave_freq, |x, y, z| noise::ridge_3d(x, y, z, 1, self.config.seed + 1)))
+    } else {
+      None
+    };
+
+    // how far below the surface each voxel is, unclamped and before the caves are carved out
+    let depth = Array3::from_shape_fn(CHUNK_DIM, |(x, y, z)| {
+      let height = height_map[(z, x)];
+      let noise = || noise.as_ref().unwrap()[(x, y, z)];
+      let y = (origin.y + y as i32 * stride) as f32;
+      match self.config.mode {
+        DensityMode::HeightMap => height - y,
+        DensityMode::Overhangs => height - y + noise() * volume.amplitude,
+        DensityMode::Volume => noise() * volume.amplitude - y * volume.gradient,
+      }
+    });
+
+    let data = Array3::from_shape_fn(CHUNK_DIM, |idx| {
+      let mut density = depth[idx];
+      if let Some(caves) = &caves {
+        // the ridges' slope is about the frequency, so this is roughly the distance to the cave wall
+        density = density.min((volume.cave_threshold - caves[idx]) / volume.cave_freq);
+      }
+      // sharp vs smooth
+      if self.config.cutoff {
+        if density >= 1.0 { 1.0 } else { 0.0 }
       } else {
-        // sharp vs smooth
-        if self.config.cutoff {
-          0.0
-        } else {
-          height - (y as f32)
-        }
+        density.min(1.0)
       }
     });
 
-    Chunk {
-      x, z, data
+    // cave walls are whatever the voxel would have been, rock unless they break the surface
+    let materials = Array3::from_shape_fn(CHUNK_DIM, |(x, y, z)| {
+      let depth = depth[(x, y, z)];
+      let biome = &self.config.biomes[biomes[(z, x)]];
+      self.material_at(biome, (origin.y + y as i32 * stride) as f32 + depth, depth)
+    });
+
+    (data, materials)
+  }
+
+  /// 3d noise for a chunk's worth of samples starting at height `y`, indexed like the chunk.
+  /// Every column samples it at its position in `warped`, so the volume is warped along with
+  /// the height map. `noise` is handed the points scaled by `freq`.
+  fn noise_3d(
+    &self,
+    warped: &Array2<(f32, f32)>,
+    y: i32,
+    stride: i32,
+    freq: f32,
+    noise: impl Fn(f32, f32, f32) -> f32,
+  ) -> Array3<f32> {
+    Array3::from_shape_fn(CHUNK_DIM, |(i, j, k)| {
+      let (x, z) = warped[(k, i)];
+      let y = (y + j as i32 * stride) as f32;
+      noise(x * freq, y * freq, z * freq)
+    })
+  }
+
+  /// Surface heights of `width` by `depth` columns spaced `stride` voxels apart from
+  /// (x, z), and the biome each column belongs to, both indexed by (z, x). Sampled in
+  /// world space, so the padding of a chunk is exactly its neighbours' voxels.
+  fn height_map(&self, x: i32, z: i32, width: usize, depth: usize, stride: i32) -> (Array2<f32>, Array2<usize>) {
+    let columns = Array2::from_shape_fn((depth, width), |(j, i)| {
+      self.column(x + i as i32 * stride, z + j as i32 * stride)
+    });
+    let mut heights = columns.map(|column| column.0);
+    if let Some(erosion) = &self.erosion {
+      erosion.erode(&mut heights, x, z, stride, |x, z| self.column(x, z).0);
+    }
+    (heights, columns.map(|column| column.1))
+  }
+
+  /// Water surface over each of `width` by `depth` columns spaced `stride` voxels apart from
+  /// (x, z), indexed by (z, x). None where the column is dry, that is where neither the sea
+  /// nor a lake reaches above its surface.
+  pub fn water_map(&self, x: i32, z: i32, width: usize, depth: usize, stride: i32) -> Array2<Option<f32>> {
+    let heights = self.height_map(x, z, width, depth, stride).0;
+    let lakes = self.lakes.as_ref().map(|lakes| {
+      lakes.levels(x, z, width, depth, stride, |x, z, width, depth, stride| self.height_map(x, z, width, depth, stride).0)
+    });
+    Array2::from_shape_fn((depth, width), |idx| {
+      let lake = lakes.as_ref().and_then(|lakes| lakes[idx]);
+      let level = lake.map_or(self.config.sea_level, |lake| lake.max(self.config.sea_level));
+      if heights[idx] < level { Some(level) } else { None }
+    })
+  }
+
+  /// Water surface over the voxel column holding (x, z), None if it's dry.
+  /// Like `surface_height`, edits are not taken into account.
+  pub fn water_level(&self, x: f32, z: f32) -> Option<f32> {
+    self.water_map(x.floor() as i32, z.floor() as i32, 1, 1, 1)[(0, 0)]
+  }
+
+  /// Uneroded surface height of the column at (x, z) and the biome it belongs to.
+  fn column(&self, x: i32, z: i32) -> (f32, usize) {
+    let (x, z) = self.warp(x, z);
+    let weights = biome::biome_weights(&self.config, biome::climate(&self.config, x, z));
+    // heights are blended across biome borders, materials come from the closest biome
+    let height = weights.iter()
+      .map(|&(i, weight)| self.config.biomes[i].height.sample(self.config.seed, x, z) * weight)
+      .sum::<f32>();
+    (height, weights[0].0)
+  }
+
+  /// where the column at (x, z) samples its noise, 2d and 3d alike
+  fn warp(&self, x: i32, z: i32) -> (f32, f32) {
+    match &self.config.warp {
+      Some(warp) => noise::warp(warp, self.config.seed, x as f32, z as f32),
+      None => (x as f32, z as f32),
+    }
+  }
+
+  /// Height the generated terrain's surface crosses the column at (x, z), interpolated
+  /// between the voxel columns around it. Only samples the noise, nothing is meshed,
+  /// and edits are not taken into account, see `ChunkManager::surface_height` for those.
+  /// Follows the height map alone, so the 3d noise of the other `DensityMode`s and caves are ignored.
+  pub fn surface_height(&self, x: f32, z: f32) -> f32 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let heights = self.height_map(x0 as i32, z0 as i32, 2, 2, 1).0
+      // the densities cross zero at the height itself, or at its floor when cut off sharply
+      .map(|h| if self.config.cutoff { h.floor() } else { *h });
+    let (tx, tz) = (x - x0, z - z0);
+    let near = heights[(0, 0)] + (heights[(0, 1)] - heights[(0, 0)]) * tx;
+    let far = heights[(1, 0)] + (heights[(1, 1)] - heights[(1, 0)]) * tx;
+    near + (far - near) * tz
+  }
+
+  /// Material of a voxel `depth` below a surface at `height` in `biome`. Air above the
+  /// surface gets the surface's material, so the mesh picks it up on either side.
+  fn material_at(&self, biome: &BiomeConfig, height: f32, depth: f32) -> VoxelMaterial {
+    if height > self.config.rock_height || depth > biome.soil_depth {
+      VoxelMaterial::Rock
+    } else if height < self.config.sand_height {
+      VoxelMaterial::Sand
+    } else if depth > 1.0 {
+      biome.soil
+    } else {
+      biome.surface
     }
   }
 }
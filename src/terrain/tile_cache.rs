@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// columns per side of the part of a tile that belongs to it, the rest is its margin
+pub const TILE: i32 = 64;
+/// tiles kept around unless `reserve`d for more, chunks next to each other tend to need the same ones
+const CACHED_TILES: usize = 64;
+
+/// tiles are keyed by the stride they were made at and their position in tiles
+pub type TileKey = (i32, i32, i32);
+
+/// The most recently used tiles of the height map passes that work on tiles, like `Erosion`.
+/// Those are far too slow to make over again whenever a chunk needs one.
+///
+/// Every pass splits the height map into the same `TILE` columns wide squares, at the stride
+/// of the columns, and makes each of them with a margin of its own choosing around it.
+pub struct TileCache<T> {
+  margin: i32,
+  capacity: usize,
+  tiles: Mutex<Tiles<T>>,
+}
+
+struct Tiles<T> {
+  /// every tile with when it was last used
+  cached: HashMap<TileKey, (Arc<T>, u64)>,
+  /// ticks on every lookup
+  clock: u64,
+}
+
+impl<T> TileCache<T> {
+  /// for tiles made with `margin` columns around each side
+  pub fn new(margin: i32) -> TileCache<T> {
+    TileCache { margin, capacity: CACHED_TILES, tiles: Mutex::new(Tiles { cached: HashMap::new(), clock: 0 }) }
+  }
+
+  /// columns per side of a tile, margin included
+  pub fn side(&self) -> usize {
+    (TILE + 2 * self.margin) as usize
+  }
+
+  /// the first column of tile (kx, kz), margin included
+  pub fn corner(&self, kx: i32, kz: i32) -> (i32, i32) {
+    (kx * TILE - self.margin, kz * TILE - self.margin)
+  }
+
+  /// index of column (sx, sz) into tile (kx, kz), as (z, x)
+  pub fn local(&self, kx: i32, kz: i32, sx: i32, sz: i32) -> (usize, usize) {
+    let (x0, z0) = self.corner(kx, kz);
+    ((sz - z0) as usize, (sx - x0) as usize)
+  }
+
+  /// Makes room for every tile a `span` columns wide square of the map needs, at each of
+  /// `strides` strides, when each column reads the `reads` closest tiles along either axis.
+  pub fn reserve(&mut self, span: i32, strides: usize, reads: i32) {
+    // a square can straddle one more tile than it would fill
+    let across = (span / TILE + 1 + reads) as usize;
+    self.capacity = (across * across * strides).max(CACHED_TILES);
+  }
+
+  /// how wide a square of the map the tiles a `span` columns wide square reads are made from,
+  /// when each column reads the tile it's in
+  pub fn reach(&self, span: i32) -> i32 {
+    (span / TILE + 2) * TILE + 2 * self.margin
+  }
+
+  /// The tile at `key`, made by `make` if it isn't cached. It's made without holding
+  /// the lock, so two workers may end up making the same tile.
+  pub fn get_or_make(&self, key: TileKey, make: impl FnOnce() -> T) -> Arc<T> {
+    {
+      let mut tiles = self.tiles.lock().unwrap();
+      let Tiles { cached, clock } = &mut *tiles;
+      *clock += 1;
+      if let Some((tile, last_used)) = cached.get_mut(&key) {
+        *last_used = *clock;
+        return tile.clone();
+      }
+    }
+
+    let tile = Arc::new(make());
+    let mut tiles = self.tiles.lock().unwrap();
+    let Tiles { cached, clock } = &mut *tiles;
+    cached.insert(key, (tile.clone(), *clock));
+    while cached.len() > self.capacity {
+      let oldest = *cached.iter()
+        .min_by_key(|(_, (_, last_used))| *last_used)
+        .map(|(key, _)| key)
+        .unwrap();
+      cached.remove(&oldest);
+    }
+    tile
+  }
+}
@@ -0,0 +1,193 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use amethyst::core::math::Vector3;
+use amethyst::renderer::{types::MeshData, rendy::mesh::{MeshBuilder, Normal, Position, TexCoord}};
+use ndarray::Array2;
+use crate::config::generator::LakeConfig;
+use super::coord::VoxelPos;
+use super::terrain_gen::{TerrainGenerator, WIDTH, DEPTH, HEIGHT};
+use super::tile_cache::{TileCache, TILE};
+
+/// columns filled around each side of a tile. A lake reaching further than this
+/// past its tile gets cut off, and comes out shallower than it should
+const MARGIN: i32 = 64;
+
+/// Finds the basins of the height map and fills them up to where they would spill over.
+///
+/// Like `Erosion`, the world is split into tiles that are filled with a margin around
+/// them. Every column takes its lake from the tile it is in, so a lake's level only
+/// depends on where it is, not on which chunk asked for it.
+pub struct Lakes {
+  config: LakeConfig,
+  /// lake surface of every column of each cached tile, None where it's dry
+  tiles: TileCache<Array2<Option<f32>>>,
+}
+
+impl Lakes {
+  pub fn new(config: LakeConfig) -> Lakes {
+    Lakes { config, tiles: TileCache::new(MARGIN) }
+  }
+
+  /// see `TileCache::reserve`, columns only read the tile they're in
+  pub fn reserve(&mut self, span: i32, strides: usize) {
+    self.tiles.reserve(span, strides, 1);
+  }
+
+  /// how wide a square of the height map the tiles for a `span` columns wide square are filled from
+  pub fn reach(&self, span: i32) -> i32 {
+    self.tiles.reach(span)
+  }
+
+  /// Lake surface over each of `width` by `depth` columns spaced `stride` voxels apart
+  /// from (x, z), indexed by (z, x), None where there is no lake. `height_map` gives the
+  /// surface heights of any such block of columns, the tiles are filled from it.
+  pub fn levels(
+    &self,
+    x: i32,
+    z: i32,
+    width: usize,
+    depth: usize,
+    stride: i32,
+    height_map: impl Fn(i32, i32, usize, usize, i32) -> Array2<f32>,
+  ) -> Array2<Option<f32>> {
+    let (sx, sz) = (x.div_euclid(stride), z.div_euclid(stride));
+    Array2::from_shape_fn((depth, width), |(j, i)| {
+      let (sx, sz) = (sx + i as i32, sz + j as i32);
+      let (kx, kz) = (sx.div_euclid(TILE), sz.div_euclid(TILE));
+      let tile = self.tile(stride, kx, kz, &height_map);
+      tile[self.tiles.local(kx, kz, sx, sz)]
+    })
+  }
+
+  fn tile(&self, stride: i32, kx: i32, kz: i32, height_map: &impl Fn(i32, i32, usize, usize, i32) -> Array2<f32>) -> Arc<Array2<Option<f32>>> {
+    self.tiles.get_or_make((stride, kx, kz), || {
+      let (x0, z0) = self.tiles.corner(kx, kz);
+      let side = self.tiles.side();
+      let heights = height_map(x0 * stride, z0 * stride, side, side, stride);
+      fill(&heights, self.config.min_depth)
+    })
+  }
+}
+
+/// a column waiting to be flooded, the heap pops the lowest water level first
+struct Flood(f32, (usize, usize));
+
+impl PartialEq for Flood {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for Flood {}
+
+impl PartialOrd for Flood {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Flood {
+  fn cmp(&self, other: &Self) -> Ordering {
+    other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal).then_with(|| other.1.cmp(&self.1))
+  }
+}
+
+fn neighbours((j, i): (usize, usize), (depth, width): (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+  let candidates = [(j.wrapping_sub(1), i), (j + 1, i), (j, i.wrapping_sub(1)), (j, i + 1)];
+  candidates.to_vec().into_iter().filter(move |&(j, i)| j < depth && i < width)
+}
+
+/// Priority flood: water drains off the edges of the map, and rises from there through
+/// the lowest columns first, so every basin ends up filled to the lowest point of its rim.
+/// Basins that are less than `min_depth` deep at their deepest are left dry.
+fn fill(heights: &Array2<f32>, min_depth: f32) -> Array2<Option<f32>> {
+  let dim = heights.dim();
+  let mut filled = heights.clone();
+  let mut queued = Array2::from_elem(dim, false);
+  let mut queue = BinaryHeap::new();
+  for ((j, i), &height) in heights.indexed_iter() {
+    if j == 0 || i == 0 || j == dim.0 - 1 || i == dim.1 - 1 {
+      queued[(j, i)] = true;
+      queue.push(Flood(height, (j, i)));
+    }
+  }
+  while let Some(Flood(level, idx)) = queue.pop() {
+    for neighbour in neighbours(idx, dim) {
+      if !queued[neighbour] {
+        queued[neighbour] = true;
+        filled[neighbour] = filled[neighbour].max(level);
+        queue.push(Flood(filled[neighbour], neighbour));
+      }
+    }
+  }
+
+  // group the flooded columns into lakes to find how deep each one gets
+  let mut levels = Array2::from_elem(dim, None);
+  let mut visited = Array2::from_elem(dim, false);
+  for (start, _) in heights.indexed_iter() {
+    if visited[start] || filled[start] <= heights[start] {
+      continue;
+    }
+    visited[start] = true;
+    let mut lake = vec![start];
+    let mut next = 0;
+    while next < lake.len() {
+      let idx = lake[next];
+      next += 1;
+      for neighbour in neighbours(idx, dim) {
+        if !visited[neighbour] && filled[neighbour] > heights[neighbour] {
+          visited[neighbour] = true;
+          lake.push(neighbour);
+        }
+      }
+    }
+    let deepest = lake.iter().map(|&idx| filled[idx] - heights[idx]).fold(0.0, f32::max);
+    if deepest >= min_depth {
+      for idx in lake {
+        levels[idx] = Some(filled[idx]);
+      }
+    }
+  }
+  levels
+}
+
+/// Flat quads at the water surface of every column of the cell of samples `stride` voxels
+/// apart from `origin` whose water surface lies within the cell. They face both up and down,
+/// so the water shows from underneath too. None if there is no water in the cell.
+pub fn mk_water_mesh(terrain_gen: &TerrainGenerator, origin: VoxelPos, stride: i32) -> Option<MeshData> {
+  let origin: Vector3<i32> = origin.into();
+  let levels = terrain_gen.water_map(origin.x, origin.z, WIDTH, DEPTH, stride);
+  let (bottom, top) = (origin.y as f32, (origin.y + HEIGHT as i32 * stride) as f32);
+
+  let mut vertices = Vec::new();
+  let mut normals = Vec::new();
+  let mut indices = Vec::<u16>::new();
+  for ((j, i), level) in levels.indexed_iter() {
+    let level = match level {
+      Some(level) if bottom <= *level && *level < top => *level,
+      _ => continue,
+    };
+    let (x, y, z) = ((i as i32 * stride) as f32, level - bottom, (j as i32 * stride) as f32);
+    let s = stride as f32;
+    let corners = [[x, y, z], [x, y, z + s], [x + s, y, z + s], [x + s, y, z]];
+    for &(normal, winding) in &[([0.0, 1.0, 0.0], [0, 1, 2, 0, 2, 3]), ([0.0, -1.0, 0.0], [0, 2, 1, 0, 3, 2])] {
+      let first = vertices.len() as u16;
+      vertices.extend(corners.iter().map(|&corner| Position(corner)));
+      normals.extend(corners.iter().map(|_| Normal(normal)));
+      indices.extend(winding.iter().map(|v| first + v));
+    }
+  }
+  if indices.is_empty() {
+    return None;
+  }
+
+  let tex_coords = vec![TexCoord([0.5, 0.5]); vertices.len()];
+  Some(MeshData(
+    MeshBuilder::new()
+      .with_vertices(vertices)
+      .with_vertices(normals)
+      .with_vertices(tex_coords)
+      .with_indices(indices)
+  ))
+}
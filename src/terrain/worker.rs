@@ -0,0 +1,265 @@
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::{self, Receiver, Sender}};
+use std::io;
+use std::thread;
+use amethyst::renderer::types::MeshData;
+use dashmap::DashMap;
+use super::chunk::Chunk;
+use super::coord::{ChunkPos, VoxelPos};
+use super::terrain_gen::TerrainGenerator;
+use super::surface_net::SurfaceNet;
+use super::region::{RegionStore, SavedChunk};
+use super::delta::ChunkDelta;
+use super::water::mk_water_mesh;
+use crate::config::terrain::DensityFormat;
+
+/// Flag shared between the main thread and the worker running a job,
+/// set when the chunk is no longer wanted.
+pub type CancelFlag = Arc<AtomicBool>;
+
+struct ChunkJob {
+  pos: ChunkPos,
+  cancelled: CancelFlag,
+}
+
+pub struct FinishedChunk {
+  pub pos: ChunkPos,
+  pub cancelled: CancelFlag,
+  pub chunk: Chunk,
+  pub mesh: Option<MeshData>,
+  pub water: Option<MeshData>,
+}
+
+/// Where chunk data comes from: the generator, with whatever edits the region
+/// files hold for the chunk applied on top.
+pub struct ChunkSource {
+  pub terrain_gen: TerrainGenerator,
+  pub store: RegionStore,
+  /// format chunks are kept in once loaded
+  pub format: DensityFormat,
+  /// chunks handed to the `SaveWorker` that it hasn't written out yet
+  unsaved: DashMap<ChunkPos, Arc<Chunk>>,
+  /// Writes to chunks that weren't loaded, e.g. to the padding of a neighbour that is out of
+  /// view, waiting on the `SaveWorker`. Replaced rather than appended to, so the worker can
+  /// tell whether more came in while it was saving.
+  unapplied: DashMap<ChunkPos, Arc<Vec<(VoxelPos, f32)>>>,
+}
+
+impl ChunkSource {
+  pub fn new(terrain_gen: TerrainGenerator, store: RegionStore, format: DensityFormat) -> ChunkSource {
+    ChunkSource { terrain_gen, store, format, unsaved: DashMap::new(), unapplied: DashMap::new() }
+  }
+
+  /// The chunk as last saved or evicted, with the writes made to it while it wasn't loaded.
+  /// Those are taken before anything is read, so a save landing in between can't lose them.
+  pub fn fetch(&self, pos: ChunkPos) -> Chunk {
+    let writes = self.unapplied_writes(pos);
+    let mut chunk = self.fetch_saved(pos);
+    if let Some(writes) = writes {
+      chunk.write(&writes);
+    }
+    chunk
+  }
+
+  fn fetch_saved(&self, pos: ChunkPos) -> Chunk {
+    if let Some(chunk) = self.unsaved.get(&pos) {
+      return Chunk::clone(&chunk);
+    }
+    match self.store.load_chunk(pos) {
+      Ok(Some(SavedChunk::Delta(delta))) => delta.apply(self.generate(pos)),
+      Ok(Some(SavedChunk::Full(chunk))) => chunk.with_format(self.format),
+      Ok(None) => self.generate(pos),
+      Err(e) => {
+        eprintln!("failed to load chunk {:?}, regenerating it: {}", pos, e);
+        self.generate(pos)
+      }
+    }
+  }
+
+  /// Saves only the voxels that differ from the generated chunks, unless a chunk is edited so
+  /// heavily that saving all of it is smaller. Either way every chunk is generated again to compare against.
+  pub fn save<'a>(&self, chunks: impl IntoIterator<Item = &'a Chunk>) -> io::Result<()> {
+    let chunks = chunks.into_iter()
+      .map(|chunk| (chunk, ChunkDelta::between(&self.generate(chunk.pos()), chunk)));
+    self.store.save_chunks(chunks)
+  }
+
+  /// Writes out the chunk last handed to the `SaveWorker` at `pos` and the writes made to it
+  /// while it wasn't loaded, if they're still unsaved. If the save fails they stay around,
+  /// `fetch` keeps handing the chunk out, and it can be retried.
+  pub fn flush(&self, pos: ChunkPos) -> io::Result<()> {
+    let chunk = self.unsaved.get(&pos).map(|chunk| Arc::clone(&chunk));
+    let writes = self.unapplied.get(&pos).map(|writes| Arc::clone(&writes));
+    let mut saved = match (&chunk, &writes) {
+      (None, None) => return Ok(()),
+      (Some(chunk), _) => Chunk::clone(chunk),
+      (None, Some(_)) => self.fetch_saved(pos),
+    };
+    if let Some(writes) = &writes {
+      saved.write(writes);
+    }
+    self.save(Some(&saved))?;
+
+    // either may have been replaced since, with newer edits that still need saving
+    if let Some(chunk) = chunk {
+      self.unsaved.remove_if(&pos, |_, unsaved| Arc::ptr_eq(unsaved, &chunk));
+    }
+    if let Some(writes) = writes {
+      self.unapplied.remove_if(&pos, |_, unapplied| Arc::ptr_eq(unapplied, &writes));
+    }
+    Ok(())
+  }
+
+  /// chunks with evictions or writes the `SaveWorker` hasn't saved yet
+  pub fn unsaved_chunks(&self) -> Vec<ChunkPos> {
+    let mut unsaved = self.unsaved.iter().map(|entry| *entry.key()).collect::<Vec<_>>();
+    unsaved.extend(self.unapplied.iter().map(|entry| *entry.key()).filter(|pos| !self.unsaved.contains_key(pos)));
+    unsaved
+  }
+
+  /// writes made to the chunk while it wasn't loaded that are still waiting to be saved
+  pub fn unapplied_writes(&self, pos: ChunkPos) -> Option<Arc<Vec<(VoxelPos, f32)>>> {
+    self.unapplied.get(&pos).map(|writes| Arc::clone(&writes))
+  }
+
+  /// the chunk's water surfaces, meshed apart from the terrain
+  pub fn water_mesh(&self, pos: ChunkPos) -> Option<MeshData> {
+    mk_water_mesh(&self.terrain_gen, pos.origin(), 1)
+  }
+
+  fn generate(&self, pos: ChunkPos) -> Chunk {
+    self.terrain_gen.generate_chunk(pos).with_format(self.format)
+  }
+}
+
+/// Pool of threads running jobs off the main thread, jobs that come back
+/// as None produce nothing. The workers exit once the pool is dropped and the job queue closes.
+pub struct WorkerPool<J, R> {
+  jobs: Mutex<Sender<J>>,
+  finished: Mutex<Receiver<R>>,
+}
+
+impl<J: Send + 'static, R: Send + 'static> WorkerPool<J, R> {
+  pub fn new<F>(name: &str, threads: usize, run: F) -> WorkerPool<J, R>
+    where F: Fn(J) -> Option<R> + Send + Sync + 'static {
+    let (job_tx, job_rx) = mpsc::channel::<J>();
+    let (finished_tx, finished_rx) = mpsc::channel();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let run = Arc::new(run);
+
+    for i in 0..threads.max(1) {
+      let job_rx = job_rx.clone();
+      let finished_tx = finished_tx.clone();
+      let run = run.clone();
+      thread::Builder::new()
+        .name(format!("{} {}", name, i))
+        .spawn(move || {
+          loop {
+            // the lock is only held while waiting for the next job
+            let job = match job_rx.lock().unwrap().recv() {
+              Ok(job) => job,
+              Err(_) => break,
+            };
+            if let Some(finished) = run(job) {
+              if finished_tx.send(finished).is_err() {
+                break;
+              }
+            }
+          }
+        })
+        .expect("failed to spawn worker");
+    }
+
+    WorkerPool {
+      jobs: Mutex::new(job_tx),
+      finished: Mutex::new(finished_rx),
+    }
+  }
+
+  pub fn submit(&self, job: J) {
+    // the workers only hang up when the pool is dropped
+    self.jobs.lock().unwrap()
+      .send(job)
+      .expect("workers have shut down");
+  }
+
+  /// everything finished since the last call
+  pub fn drain(&self) -> Vec<R> {
+    self.finished.lock().unwrap().try_iter().collect()
+  }
+}
+
+/// Generates and meshes chunks off the main thread.
+pub struct ChunkWorkers {
+  pool: WorkerPool<ChunkJob, FinishedChunk>,
+}
+
+impl ChunkWorkers {
+  pub fn new(threads: usize, source: Arc<ChunkSource>, surface_net: Arc<SurfaceNet>) -> ChunkWorkers {
+    let pool = WorkerPool::new("chunk worker", threads, move |job| run_job(&source, &surface_net, job));
+    ChunkWorkers { pool }
+  }
+
+  pub fn submit(&self, pos: ChunkPos, cancelled: CancelFlag) {
+    self.pool.submit(ChunkJob { pos, cancelled });
+  }
+
+  /// everything finished since the last call, cancelled jobs included
+  pub fn drain(&self) -> Vec<FinishedChunk> {
+    self.pool.drain()
+  }
+}
+
+/// Saves chunks off the main thread, since diffing them means generating them again.
+/// It runs a single thread, so saves of the same chunk are written in the order they were made.
+pub struct SaveWorker {
+  source: Arc<ChunkSource>,
+  pool: WorkerPool<ChunkPos, ()>,
+}
+
+impl SaveWorker {
+  pub fn new(source: Arc<ChunkSource>) -> SaveWorker {
+    let saving = source.clone();
+    let pool = WorkerPool::new("chunk saver", 1, move |pos| {
+      if let Err(e) = saving.flush(pos) {
+        eprintln!("failed to save chunk {:?}: {}", pos, e);
+      }
+      None
+    });
+    SaveWorker { source, pool }
+  }
+
+  /// Queues the chunk to be saved, until then `ChunkSource::fetch` hands out this copy of it.
+  /// A loaded chunk has had the writes it missed while unloaded applied, so they're dropped.
+  pub fn save(&self, chunk: Chunk) {
+    let pos = chunk.pos();
+    self.source.unapplied.remove(&pos);
+    self.source.unsaved.insert(pos, Arc::new(chunk));
+    self.pool.submit(pos);
+  }
+
+  /// queues writes to a chunk that isn't loaded, to be saved on top of what it holds already
+  pub fn write(&self, pos: ChunkPos, writes: &[(VoxelPos, f32)]) {
+    let mut unapplied = self.source.unapplied_writes(pos)
+      .map(|unapplied| Vec::clone(&unapplied))
+      .unwrap_or_default();
+    unapplied.extend_from_slice(writes);
+    self.source.unapplied.insert(pos, Arc::new(unapplied));
+    self.pool.submit(pos);
+  }
+}
+
+fn run_job(source: &ChunkSource, surface_net: &SurfaceNet, job: ChunkJob) -> Option<FinishedChunk> {
+  let ChunkJob { pos, cancelled } = job;
+  if cancelled.load(Ordering::Relaxed) {
+    return None;
+  }
+  let chunk = source.fetch(pos);
+
+  if cancelled.load(Ordering::Relaxed) {
+    return None;
+  }
+  let mesh = surface_net.mk_mesh(surface_net.mk_cubes(&chunk));
+  let water = source.water_mesh(pos);
+
+  Some(FinishedChunk { pos, cancelled, chunk, mesh, water })
+}